@@ -1,45 +1,72 @@
-extern crate regex;
-mod parser;
-mod mir;
-use mir::Mir;
-use parser::ast::ParserState;
-use parser::lexer::Tokenizer;
+extern crate asm_8051_rs;
+
+use asm_8051_rs::mir::{Mir, Options};
+use asm_8051_rs::parser::ast::ParserState;
+use asm_8051_rs::parser::lexer::Tokenizer;
 use std::env;
 use std::fs::File;
-use std::io::Read;
+use std::process;
 
 fn main() {
-    let args: Vec<_> = env::args().collect();
-    if args.len() > 1 {
-        let mut f = File::open(&args[1]).unwrap();
-        let mut program = String::new();
-        if let Err(e) = f.read_to_string(&mut program) {
-            println!("Error reading file: {:?}", e);
-            return;
-        }
-        let tokens = match Tokenizer::tokenize(&program) {
-            Ok(tokens) => tokens,
+    let args: Vec<_> = env::args().skip(1).collect();
+    let options = Options {
+        expand_branches: args.iter().any(|arg| arg == "--expand-branches"),
+        case_sensitive: args.iter().any(|arg| arg == "--case-sensitive"),
+    };
+    if let Some(path) = args.iter().find(|arg| !arg.starts_with("--")) {
+        let f = match File::open(path) {
+            Ok(f) => f,
             Err(e) => {
-                println!("Error: {:?}", e);
-                return;
+                eprintln!("Error opening file: {}", e);
+                process::exit(1);
             }
         };
+        let mut tokens = Vec::new();
+        let mut tokenizer_errors = Vec::new();
+        for item in Tokenizer::new().stream(f) {
+            match item {
+                Ok(token) => tokens.push(token),
+                Err(e) => {
+                    eprintln!("Error: {}", e);
+                    tokenizer_errors.push(e);
+                }
+            }
+        }
 
-        let program = match ParserState::parse(tokens) {
+        // the parser still runs after tokenizer errors, so that its errors get reported too
+        let program = match ParserState::parse(tokens, path) {
             Ok(program) => program,
-            Err(e) => {
-                println!("Parser error: {:?}", e);
-                return;
+            Err(errors) => {
+                for e in errors {
+                    eprintln!("Error at {}", e);
+                }
+                process::exit(1);
             }
         };
+        if !tokenizer_errors.is_empty() {
+            process::exit(1);
+        }
 
-        let mir = match Mir::from_program(program) {
+        let mir = match Mir::from_program(program, &options) {
             Ok(mir) => mir,
-            Err(e) => {
-                println!("MIR parsing error: {:?}", e);
-                return;
+            Err(errors) => {
+                for e in errors {
+                    eprintln!("Error at {}", e);
+                }
+                process::exit(1);
             }
         };
-        println!("{}", mir.gen_intel_hex());
+        for (addr, span) in mir.expanded_branches() {
+            eprintln!("Expanded the branch at {} ({:04X}h) into a long jump", span, addr);
+        }
+        match mir.gen_intel_hex() {
+            Ok(hex) => println!("{}", hex),
+            Err(errors) => {
+                for e in errors {
+                    eprintln!("Error at {}", e);
+                }
+                process::exit(1);
+            }
+        }
     }
 }
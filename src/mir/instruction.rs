@@ -1,26 +1,8 @@
-use parser::ast::Operand;
+use parser::ast::{Operand, Value};
+use parser::expr::{EvalError, Expr};
 use parser::keywords::{Operator, Register as Reg};
 use std::collections::HashMap;
-
-#[derive(Clone, Debug, PartialEq)]
-pub enum Address {
-    Number(u16),
-    Label(String),
-}
-
-impl Address {
-    pub fn to_u16(&self, labels: &HashMap<String, u16>) -> Result<u16, InstructionError> {
-        match *self {
-            Address::Number(x) => Ok(x),
-            Address::Label(ref s) => {
-                labels
-                    .get(s)
-                    .map(|x| *x)
-                    .ok_or(InstructionError::UnknownLabel(s.clone()))
-            }
-        }
-    }
-}
+use std::fmt::{self, Display};
 
 #[derive(Clone, Debug)]
 pub enum InstructionError {
@@ -35,79 +17,168 @@ pub enum InstructionError {
         pos: u8,
     },
     UnknownLabel(String),
+    DivisionByZero,
+    /// A bit address outside of 0..255, or a `byte.bit` whose byte isn't bit-addressable
+    InvalidBitAddress(i32),
+    /// A `byte.bit` whose bit number isn't 0..7
+    InvalidBitNumber(i32),
+    ValueOutOfRange(i32),
+    /// The code runs past the end of the 64 KB code memory; holds the address it would end at
+    AddressOutOfRange(i32),
+    DuplicateSymbol(String),
+    CircularDefinition(String),
+    /// The target of a relative branch is further than -128..127 bytes away
+    BranchOutOfRange {
+        instruction: Instruction,
+        target: i32,
+        excess: i32,
+    },
+    /// The target of an ACALL or AJMP is outside of the 2 KB page of the next instruction
+    TargetOutsidePage {
+        instruction: Instruction,
+        target: i32,
+        page: i32,
+    },
+}
+
+impl Display for InstructionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            InstructionError::InvalidNumOperands { operator, num, expected } => {
+                write!(f, "{} takes {} operands, found {}", operator, expected, num)
+            }
+            InstructionError::InvalidOperand { operator, .. } => {
+                write!(f, "Invalid operand for {}", operator)
+            }
+            InstructionError::UnknownLabel(ref s) => write!(f, "Unknown symbol {}", s),
+            InstructionError::DivisionByZero => write!(f, "Division by zero"),
+            InstructionError::InvalidBitAddress(addr) => {
+                write!(f, "{} isn't the address of a bit", addr)
+            }
+            InstructionError::InvalidBitNumber(bit) => {
+                write!(f, "Bit number {} isn't in 0..7", bit)
+            }
+            InstructionError::ValueOutOfRange(value) => {
+                write!(f, "Value {} is out of range", value)
+            }
+            InstructionError::AddressOutOfRange(end) => {
+                write!(f, "The code runs past FFFFh, up to {:X}h", end)
+            }
+            InstructionError::DuplicateSymbol(ref s) => write!(f, "Symbol {} is already defined", s),
+            InstructionError::CircularDefinition(ref s) => {
+                write!(f, "Symbol {} is defined in terms of itself", s)
+            }
+            InstructionError::BranchOutOfRange { target, excess, .. } => {
+                write!(f,
+                       "Branch target {:04X}h is {} bytes out of reach",
+                       target,
+                       excess)
+            }
+            InstructionError::TargetOutsidePage { target, page, .. } => {
+                write!(f,
+                       "Target {:04X}h is outside of the 2 KB page at {:04X}h",
+                       target,
+                       page)
+            }
+        }
+    }
+}
+
+impl From<EvalError> for InstructionError {
+    fn from(err: EvalError) -> InstructionError {
+        match err {
+            EvalError::UnknownSymbol(s) => InstructionError::UnknownLabel(s),
+            EvalError::DivisionByZero => InstructionError::DivisionByZero,
+            EvalError::InvalidBitAddress(addr) => InstructionError::InvalidBitAddress(addr),
+            EvalError::InvalidBitNumber(bit) => InstructionError::InvalidBitNumber(bit),
+        }
+    }
+}
+
+/// The encoding picked for a generic CALL or JMP, in order of increasing size
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum JumpForm {
+    Short,
+    Absolute,
+    Long,
 }
 
 #[derive(Clone, Debug)]
 pub enum Instruction {
-    Acall(Address),
+    Acall(Expr),
     AddAReg(u8),
-    AddADirect(u8),
+    AddADirect(Expr),
     AddAIndirReg(u8),
-    AddAData(u8),
+    AddAData(Expr),
     AddcAReg(u8),
-    AddcADirect(u8),
+    AddcADirect(Expr),
     AddcAIndirReg(u8),
-    AddcAData(u8),
-    Ajmp(Address),
+    AddcAData(Expr),
+    Ajmp(Expr),
     AnlAReg(u8),
-    AnlADirect(u8),
+    AnlADirect(Expr),
     AnlAIndirReg(u8),
-    AnlAData(u8),
-    AnlDirectA(u8),
-    AnlDirectData(u8, u8),
-    AnlCBit(u8),
-    AnlCNegBit(u8),
-    CjneADirRel(u8, Address),
-    CjneADataRel(u8, Address),
-    CJneRegDataRel(u8, u8, Address),
-    CjneIndirRegDataRel(u8, u8, Address),
+    AnlAData(Expr),
+    AnlDirectA(Expr),
+    AnlDirectData(Expr, Expr),
+    AnlCBit(Expr),
+    AnlCNegBit(Expr),
+    Call(Expr, JumpForm),
+    CjneADirRel(Expr, Expr),
+    CjneADataRel(Expr, Expr),
+    CJneRegDataRel(u8, Expr, Expr),
+    CjneIndirRegDataRel(u8, Expr, Expr),
     ClrA,
     ClrC,
-    ClrBit(u8),
+    ClrBit(Expr),
     CplA,
     CplC,
-    CplBit(u8),
+    CplBit(Expr),
     DaA,
     DecA,
     DecReg(u8),
-    DecDirect(u8),
+    DecDirect(Expr),
     DecIndirReg(u8),
     DivAB,
-    DjnzRegRel(u8, Address),
-    DjnzDirectRel(u8, Address),
+    DjnzRegRel(u8, Expr),
+    DjnzDirectRel(Expr, Expr),
     IncA,
     IncReg(u8),
-    IncDirect(u8),
+    IncDirect(Expr),
     IncIndirReg(u8),
     IncDptr,
-    JbBitRel(u8, Address),
-    JbcBitRel(u8, Address),
-    JcRel(Address),
+    JbBitRel(Expr, Expr),
+    JbcBitRel(Expr, Expr),
+    JcRel(Expr),
+    Jmp(Expr, JumpForm),
     JmpIndirAPlusDptr,
-    JnbBitRel(u8, Address),
-    JncRel(Address),
-    JnzRel(Address),
-    JzRel(Address),
-    Lcall(Address),
-    Ljmp(Address),
+    JnbBitRel(Expr, Expr),
+    JncRel(Expr),
+    JnzRel(Expr),
+    JzRel(Expr),
+    Lcall(Expr),
+    Ljmp(Expr),
+    /// A conditional jump whose target is out of its reach, rewritten as a short branch
+    /// around an LJMP
+    LongBranch(Box<Instruction>),
     MovAReg(u8),
-    MovADirect(u8),
+    MovADirect(Expr),
     MovAIndirReg(u8),
-    MovAData(u8),
+    MovAData(Expr),
     MovRegA(u8),
-    MovRegDir(u8, u8),
-    MovRegData(u8, u8),
-    MovDirectA(u8),
-    MovDirectReg(u8, u8),
-    MovDirectDirect(u8, u8),
-    MovDirectIndirReg(u8, u8),
-    MovDirectData(u8, u8),
+    MovRegDir(u8, Expr),
+    MovRegData(u8, Expr),
+    MovDirectA(Expr),
+    MovDirectReg(Expr, u8),
+    MovDirectDirect(Expr, Expr),
+    MovDirectIndirReg(Expr, u8),
+    MovDirectData(Expr, Expr),
     MovIndirRegA(u8),
-    MovIndirRegDirect(u8, u8),
-    MovIndirRegData(u8, u8),
-    MovCBit(u8),
-    MovBitC(u8),
-    MovDptrData(Address),
+    MovIndirRegDirect(u8, Expr),
+    MovIndirRegData(u8, Expr),
+    MovCBit(Expr),
+    MovBitC(Expr),
+    MovDptrData(Expr),
     MovcAIndirAPlusDptr,
     MovcAIndirAPlusPc,
     MovxAIndirReg(u8),
@@ -117,15 +188,15 @@ pub enum Instruction {
     MulAB,
     Nop,
     OrlAReg(u8),
-    OrlADirect(u8),
+    OrlADirect(Expr),
     OrlAIndirReg(u8),
-    OrlAData(u8),
-    OrlDirectA(u8),
-    OrlDirectData(u8, u8),
-    OrlCBit(u8),
-    OrlCNegBit(u8),
-    PopDirect(u8),
-    PushDirect(u8),
+    OrlAData(Expr),
+    OrlDirectA(Expr),
+    OrlDirectData(Expr, Expr),
+    OrlCBit(Expr),
+    OrlCNegBit(Expr),
+    PopDirect(Expr),
+    PushDirect(Expr),
     Ret,
     Reti,
     RlA,
@@ -133,24 +204,24 @@ pub enum Instruction {
     RrA,
     RrcA,
     SetbC,
-    SetbBit(u8),
-    Sjmp(Address),
+    SetbBit(Expr),
+    Sjmp(Expr),
     SubbAReg(u8),
-    SubbADirect(u8),
+    SubbADirect(Expr),
     SubbAIndirReg(u8),
-    SubbAData(u8),
+    SubbAData(Expr),
     SwapA,
     XchAReg(u8),
-    XchADirect(u8),
+    XchADirect(Expr),
     XchAIndirReg(u8),
     XchdAIndirReg(u8),
     XrlAReg(u8),
-    XrlADirect(u8),
+    XrlADirect(Expr),
     XrlAIndirReg(u8),
-    XrlAData(u8),
-    XrlDirectA(u8),
-    XrlDirectData(u8, u8),
-    Bytes(Vec<u8>),
+    XrlAData(Expr),
+    XrlDirectA(Expr),
+    XrlDirectData(Expr, Expr),
+    Data(Vec<Value>),
 }
 
 impl Instruction {
@@ -174,6 +245,8 @@ impl Instruction {
             Instruction::AnlDirectData(_, _) => 3,
             Instruction::AnlCBit(_) => 2,
             Instruction::AnlCNegBit(_) => 2,
+            Instruction::Call(_, form) |
+            Instruction::Jmp(_, form) => if form == JumpForm::Long { 3 } else { 2 },
             Instruction::CjneADirRel(_, _) => 3,
             Instruction::CjneADataRel(_, _) => 3,
             Instruction::CJneRegDataRel(_, _, _) => 3,
@@ -207,6 +280,9 @@ impl Instruction {
             Instruction::JzRel(_) => 2,
             Instruction::Lcall(_) => 3,
             Instruction::Ljmp(_) => 3,
+            Instruction::LongBranch(ref branch) => {
+                branch.expansion(0).iter().map(|i| i.bytes()).sum()
+            }
             Instruction::MovAReg(_) => 1,
             Instruction::MovADirect(_) => 2,
             Instruction::MovAIndirReg(_) => 1,
@@ -267,7 +343,7 @@ impl Instruction {
             Instruction::XrlAData(_) => 2,
             Instruction::XrlDirectA(_) => 2,
             Instruction::XrlDirectData(_, _) => 3,
-            Instruction::Bytes(ref v) => v.len() as u16,
+            Instruction::Data(ref values) => values.iter().map(|v| v.size()).sum(),
         }
     }
 
@@ -300,13 +376,27 @@ impl Instruction {
     pub fn from_code(operator: Operator,
                      operands: Vec<Operand>)
                      -> Result<Instruction, InstructionError> {
+        let instruction = Self::decode(operator, operands.clone())?;
+        // bit names and `byte.bit` are bit addresses, which only the bit instructions take
+        if !instruction.addresses_bit() {
+            for (pos, operand) in operands.iter().enumerate() {
+                if let Operand::Direct(ref addr) = *operand {
+                    if addr.is_bit() {
+                        return Self::invalid_operand(operator, operand.clone(), pos as u8);
+                    }
+                }
+            }
+        }
+        Ok(instruction)
+    }
+
+    fn decode(operator: Operator, operands: Vec<Operand>) -> Result<Instruction, InstructionError> {
         use self::Operand::*;
         match operator {
             Operator::Acall => {
                 Self::expect_operands(operator, &operands, 1)?;
                 let address = match operands[0] {
-                    Immediate(addr) if addr >= 0 && addr <= 2048 => Address::Number(addr as u16),
-                    ImmediateId(ref id) => Address::Label(id.clone()),
+                    Address(ref addr) => addr.clone(),
                     _ => {
                         return Self::invalid_operand(operator, operands[0].clone(), 0);
                     }
@@ -320,10 +410,10 @@ impl Instruction {
                 }
                 match operands[1] {
                     Register(Reg::R(r)) => Ok(Instruction::AddAReg(r)),
-                    Direct(dir) => Ok(Instruction::AddADirect(dir)),
+                    Direct(ref dir) => Ok(Instruction::AddADirect(dir.clone())),
                     IndirectReg(Reg::R(r)) if r < 2 => Ok(Instruction::AddAIndirReg(r)),
-                    Immediate(imm) if imm >= -128 && imm <= 255 => {
-                        Ok(Instruction::AddAData(imm as u8))
+                    Immediate(ref imm) => {
+                        Ok(Instruction::AddAData(imm.clone()))
                     }
                     _ => {
                         return Self::invalid_operand(operator, operands[1].clone(), 1);
@@ -337,10 +427,10 @@ impl Instruction {
                 }
                 match operands[1] {
                     Register(Reg::R(r)) => Ok(Instruction::AddcAReg(r)),
-                    Direct(dir) => Ok(Instruction::AddcADirect(dir)),
+                    Direct(ref dir) => Ok(Instruction::AddcADirect(dir.clone())),
                     IndirectReg(Reg::R(r)) if r < 2 => Ok(Instruction::AddcAIndirReg(r)),
-                    Immediate(imm) if imm >= -128 && imm <= 255 => {
-                        Ok(Instruction::AddcAData(imm as u8))
+                    Immediate(ref imm) => {
+                        Ok(Instruction::AddcAData(imm.clone()))
                     }
                     _ => {
                         return Self::invalid_operand(operator, operands[1].clone(), 1);
@@ -350,8 +440,7 @@ impl Instruction {
             Operator::Ajmp => {
                 Self::expect_operands(operator, &operands, 1)?;
                 let address = match operands[0] {
-                    Immediate(addr) if addr >= 0 && addr <= 2048 => Address::Number(addr as u16),
-                    ImmediateId(ref id) => Address::Label(id.clone()),
+                    Address(ref addr) => addr.clone(),
                     _ => {
                         return Self::invalid_operand(operator, operands[0].clone(), 0);
                     }
@@ -367,54 +456,67 @@ impl Instruction {
                     }
                 }
                 match (&operands[0], &operands[1]) {
-                    (&Register(Reg::A), &Register(Reg::R(r))) => Ok(Instruction::AnlAReg(r)),
-                    (&Register(Reg::A), &Direct(addr)) => Ok(Instruction::AnlADirect(addr)),
-                    (&Register(Reg::A), &IndirectReg(Reg::R(r))) if r < 2 => {
-                        Ok(Instruction::AnlAIndirReg(r))
+                    (Register(Reg::A), Register(Reg::R(r))) => Ok(Instruction::AnlAReg(*r)),
+                    (Register(Reg::A), Direct(addr)) => {
+                        Ok(Instruction::AnlADirect(addr.clone()))
+                    }
+                    (Register(Reg::A), IndirectReg(Reg::R(r))) if *r < 2 => {
+                        Ok(Instruction::AnlAIndirReg(*r))
+                    }
+                    (Register(Reg::A), Immediate(imm)) => {
+                        Ok(Instruction::AnlAData(imm.clone()))
                     }
-                    (&Register(Reg::A), &Immediate(imm)) if imm >= -128 && imm <= 255 => {
-                        Ok(Instruction::AnlAData(imm as u8))
+                    (Direct(addr), Register(Reg::A)) => {
+                        Ok(Instruction::AnlDirectA(addr.clone()))
                     }
-                    (&Direct(addr), &Register(Reg::A)) => Ok(Instruction::AnlDirectA(addr)),
-                    (&Direct(addr), &Immediate(imm)) if imm >= -128 && imm <= 255 => {
-                        Ok(Instruction::AnlDirectData(addr, imm as u8))
+                    (Direct(addr), Immediate(imm)) => {
+                        Ok(Instruction::AnlDirectData(addr.clone(), imm.clone()))
+                    }
+                    (Register(Reg::C), Direct(addr)) => {
+                        Ok(Instruction::AnlCBit(addr.clone()))
+                    }
+                    (Register(Reg::C), ComplementedBit(addr)) => {
+                        Ok(Instruction::AnlCNegBit(addr.clone()))
                     }
-                    (&Register(Reg::C), &Direct(addr)) => Ok(Instruction::AnlCBit(addr)),
                     _ => {
                         return Self::invalid_operand(operator, operands[1].clone(), 1);
                     }
                 }
             }
             Operator::Call => {
-                panic!("CALL not yet supported!");
+                Self::expect_operands(operator, &operands, 1)?;
+                match operands[0] {
+                    Address(ref addr) => Ok(Instruction::Call(addr.clone(), JumpForm::Absolute)),
+                    _ => Self::invalid_operand(operator, operands[0].clone(), 0),
+                }
             }
             Operator::Cjne => {
                 Self::expect_operands(operator, &operands, 3)?;
                 match operands[0] {
-                    Register(Reg::A) |
-                    Register(Reg::R(_)) |
-                    IndirectReg(_) => (),
+                    Register(Reg::A) | Register(Reg::R(_)) => (),
+                    IndirectReg(Reg::R(r)) if r < 2 => (),
                     _ => {
                         return Self::invalid_operand(operator, operands[0].clone(), 0);
                     }
                 }
                 let rel = match operands[2] {
-                    ImmediateId(ref addr) => Address::Label(addr.clone()),
+                    Address(ref addr) => addr.clone(),
                     _ => {
                         return Self::invalid_operand(operator, operands[2].clone(), 2);
                     }
                 };
                 match (&operands[0], &operands[1]) {
-                    (&Register(Reg::A), &Direct(addr)) => Ok(Instruction::CjneADirRel(addr, rel)),
-                    (&Register(Reg::A), &Immediate(imm)) if imm >= -128 && imm <= 255 => {
-                        Ok(Instruction::CjneADataRel(imm as u8, rel))
+                    (Register(Reg::A), Direct(addr)) => {
+                        Ok(Instruction::CjneADirRel(addr.clone(), rel))
+                    }
+                    (Register(Reg::A), Immediate(imm)) => {
+                        Ok(Instruction::CjneADataRel(imm.clone(), rel))
                     }
-                    (&Register(Reg::R(r)), &Immediate(imm)) if imm >= -128 && imm <= 255 => {
-                        Ok(Instruction::CJneRegDataRel(r, imm as u8, rel))
+                    (Register(Reg::R(r)), Immediate(imm)) => {
+                        Ok(Instruction::CJneRegDataRel(*r, imm.clone(), rel))
                     }
-                    (&IndirectReg(Reg::R(r)), &Immediate(imm)) if imm >= -128 && imm <= 255 &&
-                                                                  r < 2 => {
-                        Ok(Instruction::CjneIndirRegDataRel(r, imm as u8, rel))
+                    (IndirectReg(Reg::R(r)), Immediate(imm)) if *r < 2 => {
+                        Ok(Instruction::CjneIndirRegDataRel(*r, imm.clone(), rel))
                     }
                     _ => {
                         return Self::invalid_operand(operator, operands[1].clone(), 1);
@@ -426,7 +528,7 @@ impl Instruction {
                 match operands[0] {
                     Register(Reg::A) => Ok(Instruction::ClrA),
                     Register(Reg::C) => Ok(Instruction::ClrC),
-                    Direct(addr) => Ok(Instruction::ClrBit(addr)),
+                    Direct(ref addr) => Ok(Instruction::ClrBit(addr.clone())),
                     _ => {
                         return Self::invalid_operand(operator, operands[0].clone(), 0);
                     }
@@ -437,7 +539,7 @@ impl Instruction {
                 match operands[0] {
                     Register(Reg::A) => Ok(Instruction::CplA),
                     Register(Reg::C) => Ok(Instruction::CplC),
-                    Direct(addr) => Ok(Instruction::CplBit(addr)),
+                    Direct(ref addr) => Ok(Instruction::CplBit(addr.clone())),
                     _ => {
                         return Self::invalid_operand(operator, operands[0].clone(), 0);
                     }
@@ -457,18 +559,22 @@ impl Instruction {
                 match operands[0] {
                     Register(Reg::A) => Ok(Instruction::DecA),
                     Register(Reg::R(r)) => Ok(Instruction::DecReg(r)),
-                    Direct(addr) => Ok(Instruction::DecDirect(addr)),
-                    IndirectReg(Reg::R(r)) => Ok(Instruction::DecIndirReg(r)),
+                    Direct(ref addr) => Ok(Instruction::DecDirect(addr.clone())),
+                    IndirectReg(Reg::R(r)) if r < 2 => Ok(Instruction::DecIndirReg(r)),
                     _ => {
                         return Self::invalid_operand(operator, operands[0].clone(), 0);
                     }
                 }
             }
             Operator::Div => {
+                // `DIV AB`, or the same registers written as `A, B`
+                if operands.len() == 1 && operands[0] == Register(Reg::AB) {
+                    return Ok(Instruction::DivAB);
+                }
                 Self::expect_operands(operator, &operands, 2)?;
                 match operands[0] {
                     Register(Reg::A) => {
-                        if operands[1] == Direct(0xF0) {
+                        if operands[1] == Direct(Expr::Number(0xF0)) {
                             Ok(Instruction::DivAB)
                         } else {
                             return Self::invalid_operand(operator, operands[1].clone(), 1);
@@ -482,14 +588,14 @@ impl Instruction {
             Operator::Djnz => {
                 Self::expect_operands(operator, &operands, 2)?;
                 let rel = match operands[1] {
-                    ImmediateId(ref addr) => Address::Label(addr.clone()),
+                    Address(ref addr) => addr.clone(),
                     _ => {
                         return Self::invalid_operand(operator, operands[1].clone(), 1);
                     }
                 };
                 match operands[0] {
                     Register(Reg::R(r)) => Ok(Instruction::DjnzRegRel(r, rel)),
-                    Direct(addr) => Ok(Instruction::DjnzDirectRel(addr, rel)),
+                    Direct(ref addr) => Ok(Instruction::DjnzDirectRel(addr.clone(), rel)),
                     _ => {
                         return Self::invalid_operand(operator, operands[0].clone(), 0);
                     }
@@ -500,8 +606,8 @@ impl Instruction {
                 match operands[0] {
                     Register(Reg::A) => Ok(Instruction::IncA),
                     Register(Reg::R(r)) => Ok(Instruction::IncReg(r)),
-                    Direct(addr) => Ok(Instruction::IncDirect(addr)),
-                    IndirectReg(Reg::R(r)) => Ok(Instruction::IncIndirReg(r)),
+                    Direct(ref addr) => Ok(Instruction::IncDirect(addr.clone())),
+                    IndirectReg(Reg::R(r)) if r < 2 => Ok(Instruction::IncIndirReg(r)),
                     Register(Reg::DPTR) => Ok(Instruction::IncDptr),
                     _ => {
                         return Self::invalid_operand(operator, operands[0].clone(), 0);
@@ -511,13 +617,13 @@ impl Instruction {
             Operator::Jb => {
                 Self::expect_operands(operator, &operands, 2)?;
                 let rel = match operands[1] {
-                    ImmediateId(ref addr) => Address::Label(addr.clone()),
+                    Address(ref addr) => addr.clone(),
                     _ => {
                         return Self::invalid_operand(operator, operands[1].clone(), 1);
                     }
                 };
                 match operands[0] {
-                    Direct(addr) => Ok(Instruction::JbBitRel(addr, rel)),
+                    Direct(ref addr) => Ok(Instruction::JbBitRel(addr.clone(), rel)),
                     _ => {
                         return Self::invalid_operand(operator, operands[0].clone(), 0);
                     }
@@ -526,13 +632,13 @@ impl Instruction {
             Operator::Jbc => {
                 Self::expect_operands(operator, &operands, 2)?;
                 let rel = match operands[1] {
-                    ImmediateId(ref addr) => Address::Label(addr.clone()),
+                    Address(ref addr) => addr.clone(),
                     _ => {
                         return Self::invalid_operand(operator, operands[1].clone(), 1);
                     }
                 };
                 match operands[0] {
-                    Direct(addr) => Ok(Instruction::JbcBitRel(addr, rel)),
+                    Direct(ref addr) => Ok(Instruction::JbcBitRel(addr.clone(), rel)),
                     _ => {
                         return Self::invalid_operand(operator, operands[0].clone(), 0);
                     }
@@ -541,7 +647,7 @@ impl Instruction {
             Operator::Jc => {
                 Self::expect_operands(operator, &operands, 1)?;
                 match operands[0] {
-                    ImmediateId(ref addr) => Ok(Instruction::JcRel(Address::Label(addr.clone()))),
+                    Address(ref addr) => Ok(Instruction::JcRel(addr.clone())),
                     _ => {
                         return Self::invalid_operand(operator, operands[0].clone(), 0);
                     }
@@ -550,6 +656,7 @@ impl Instruction {
             Operator::Jmp => {
                 Self::expect_operands(operator, &operands, 1)?;
                 match operands[0] {
+                    Address(ref addr) => Ok(Instruction::Jmp(addr.clone(), JumpForm::Short)),
                     IndirectSum(Reg::A, Reg::DPTR) => Ok(Instruction::JmpIndirAPlusDptr),
                     _ => {
                         return Self::invalid_operand(operator, operands[0].clone(), 0);
@@ -559,13 +666,13 @@ impl Instruction {
             Operator::Jnb => {
                 Self::expect_operands(operator, &operands, 2)?;
                 let rel = match operands[1] {
-                    ImmediateId(ref addr) => Address::Label(addr.clone()),
+                    Address(ref addr) => addr.clone(),
                     _ => {
                         return Self::invalid_operand(operator, operands[1].clone(), 1);
                     }
                 };
                 match operands[0] {
-                    Direct(addr) => Ok(Instruction::JnbBitRel(addr, rel)),
+                    Direct(ref addr) => Ok(Instruction::JnbBitRel(addr.clone(), rel)),
                     _ => {
                         return Self::invalid_operand(operator, operands[0].clone(), 0);
                     }
@@ -574,7 +681,7 @@ impl Instruction {
             Operator::Jnc => {
                 Self::expect_operands(operator, &operands, 1)?;
                 match operands[0] {
-                    ImmediateId(ref addr) => Ok(Instruction::JncRel(Address::Label(addr.clone()))),
+                    Address(ref addr) => Ok(Instruction::JncRel(addr.clone())),
                     _ => {
                         return Self::invalid_operand(operator, operands[0].clone(), 0);
                     }
@@ -583,7 +690,7 @@ impl Instruction {
             Operator::Jnz => {
                 Self::expect_operands(operator, &operands, 1)?;
                 match operands[0] {
-                    ImmediateId(ref addr) => Ok(Instruction::JnzRel(Address::Label(addr.clone()))),
+                    Address(ref addr) => Ok(Instruction::JnzRel(addr.clone())),
                     _ => {
                         return Self::invalid_operand(operator, operands[0].clone(), 0);
                     }
@@ -592,7 +699,7 @@ impl Instruction {
             Operator::Jz => {
                 Self::expect_operands(operator, &operands, 1)?;
                 match operands[0] {
-                    ImmediateId(ref addr) => Ok(Instruction::JzRel(Address::Label(addr.clone()))),
+                    Address(ref addr) => Ok(Instruction::JzRel(addr.clone())),
                     _ => {
                         return Self::invalid_operand(operator, operands[0].clone(), 0);
                     }
@@ -601,8 +708,7 @@ impl Instruction {
             Operator::Lcall => {
                 Self::expect_operands(operator, &operands, 1)?;
                 let address = match operands[0] {
-                    Immediate(addr) => Address::Number(addr as u16),
-                    ImmediateId(ref id) => Address::Label(id.clone()),
+                    Address(ref addr) => addr.clone(),
                     _ => {
                         return Self::invalid_operand(operator, operands[0].clone(), 0);
                     }
@@ -612,8 +718,7 @@ impl Instruction {
             Operator::Ljmp => {
                 Self::expect_operands(operator, &operands, 1)?;
                 let address = match operands[0] {
-                    Immediate(addr) => Address::Number(addr as u16),
-                    ImmediateId(ref id) => Address::Label(id.clone()),
+                    Address(ref addr) => addr.clone(),
                     _ => {
                         return Self::invalid_operand(operator, operands[0].clone(), 0);
                     }
@@ -634,51 +739,55 @@ impl Instruction {
                     }
                 }
                 match (&operands[0], &operands[1]) {
-                    (&Register(Reg::A), &Register(Reg::R(r))) => Ok(Instruction::MovAReg(r)),
-                    (&Register(Reg::A), &Direct(addr)) if addr != 0xE0 => {
-                        Ok(Instruction::MovADirect(addr))
+                    (Register(Reg::A), Register(Reg::R(r))) => Ok(Instruction::MovAReg(*r)),
+                    (Register(Reg::A), Direct(addr)) if addr.constant() != Some(0xE0) => {
+                        Ok(Instruction::MovADirect(addr.clone()))
                     }
-                    (&Register(Reg::A), &IndirectReg(Reg::R(r))) if r < 2 => {
-                        Ok(Instruction::MovAIndirReg(r))
+                    (Register(Reg::A), IndirectReg(Reg::R(r))) if *r < 2 => {
+                        Ok(Instruction::MovAIndirReg(*r))
                     }
-                    (&Register(Reg::A), &Immediate(imm)) if imm >= -128 && imm <= 255 => {
-                        Ok(Instruction::MovAData(imm as u8))
+                    (Register(Reg::A), Immediate(imm)) => {
+                        Ok(Instruction::MovAData(imm.clone()))
                     }
-                    (&Register(Reg::R(r)), &Register(Reg::A)) => Ok(Instruction::MovRegA(r)),
-                    (&Register(Reg::R(r)), &Direct(addr)) => Ok(Instruction::MovRegDir(r, addr)),
-                    (&Register(Reg::R(r)), &Immediate(imm)) if imm >= -128 && imm <= 255 => {
-                        Ok(Instruction::MovRegData(r, imm as u8))
+                    (Register(Reg::R(r)), Register(Reg::A)) => Ok(Instruction::MovRegA(*r)),
+                    (Register(Reg::R(r)), Direct(addr)) => {
+                        Ok(Instruction::MovRegDir(*r, addr.clone()))
                     }
-                    (&Direct(addr), &Register(Reg::A)) if addr != 0xE0 => {
-                        Ok(Instruction::MovDirectA(addr))
+                    (Register(Reg::R(r)), Immediate(imm)) => {
+                        Ok(Instruction::MovRegData(*r, imm.clone()))
                     }
-                    (&Direct(addr), &Register(Reg::R(r))) => Ok(Instruction::MovDirectReg(addr, r)),
-                    (&Direct(addr), &Direct(addr2)) => {
-                        Ok(Instruction::MovDirectDirect(addr, addr2))
+                    (Direct(addr), Register(Reg::A)) if addr.constant() != Some(0xE0) => {
+                        Ok(Instruction::MovDirectA(addr.clone()))
                     }
-                    (&Direct(addr), &IndirectReg(Reg::R(r))) if r < 2 => {
-                        Ok(Instruction::MovDirectIndirReg(addr, r))
+                    (Direct(addr), Register(Reg::R(r))) => {
+                        Ok(Instruction::MovDirectReg(addr.clone(), *r))
                     }
-                    (&Direct(addr), &Immediate(imm)) if imm >= -128 && imm <= 255 => {
-                        Ok(Instruction::MovDirectData(addr, imm as u8))
+                    (Direct(addr), Direct(addr2)) => {
+                        Ok(Instruction::MovDirectDirect(addr.clone(), addr2.clone()))
                     }
-                    (&IndirectReg(Reg::R(r)), &Register(Reg::A)) if r < 2 => {
-                        Ok(Instruction::MovIndirRegA(r))
+                    (Direct(addr), IndirectReg(Reg::R(r))) if *r < 2 => {
+                        Ok(Instruction::MovDirectIndirReg(addr.clone(), *r))
+                    }
+                    (Direct(addr), Immediate(imm)) => {
+                        Ok(Instruction::MovDirectData(addr.clone(), imm.clone()))
                     }
-                    (&IndirectReg(Reg::R(r)), &Direct(addr)) if r < 2 => {
-                        Ok(Instruction::MovIndirRegDirect(r, addr))
+                    (IndirectReg(Reg::R(r)), Register(Reg::A)) if *r < 2 => {
+                        Ok(Instruction::MovIndirRegA(*r))
                     }
-                    (&IndirectReg(Reg::R(r)), &Immediate(imm)) if r < 2 && imm >= -128 &&
-                                                                  imm <= 255 => {
-                        Ok(Instruction::MovIndirRegData(r, imm as u8))
+                    (IndirectReg(Reg::R(r)), Direct(addr)) if *r < 2 => {
+                        Ok(Instruction::MovIndirRegDirect(*r, addr.clone()))
                     }
-                    (&Register(Reg::C), &Direct(addr)) => Ok(Instruction::MovCBit(addr)),
-                    (&Direct(addr), &Register(Reg::C)) => Ok(Instruction::MovBitC(addr)),
-                    (&Register(Reg::DPTR), &Immediate(addr)) if addr >= 0 && addr < 65536 => {
-                        Ok(Instruction::MovDptrData(Address::Number(addr as u16)))
+                    (IndirectReg(Reg::R(r)), Immediate(imm)) if *r < 2 => {
+                        Ok(Instruction::MovIndirRegData(*r, imm.clone()))
                     }
-                    (&Register(Reg::DPTR), &ImmediateId(ref addr)) => {
-                        Ok(Instruction::MovDptrData(Address::Label(addr.clone())))
+                    (Register(Reg::C), Direct(addr)) => {
+                        Ok(Instruction::MovCBit(addr.clone()))
+                    }
+                    (Direct(addr), Register(Reg::C)) => {
+                        Ok(Instruction::MovBitC(addr.clone()))
+                    }
+                    (Register(Reg::DPTR), Immediate(addr)) => {
+                        Ok(Instruction::MovDptrData(addr.clone()))
                     }
                     _ => {
                         return Self::invalid_operand(operator, operands[1].clone(), 1);
@@ -726,10 +835,14 @@ impl Instruction {
                 }
             }
             Operator::Mul => {
+                // `MUL AB`, or the same registers written as `A, B`
+                if operands.len() == 1 && operands[0] == Register(Reg::AB) {
+                    return Ok(Instruction::MulAB);
+                }
                 Self::expect_operands(operator, &operands, 2)?;
                 match operands[0] {
                     Register(Reg::A) => {
-                        if operands[1] == Direct(0xF0) {
+                        if operands[1] == Direct(Expr::Number(0xF0)) {
                             Ok(Instruction::MulAB)
                         } else {
                             return Self::invalid_operand(operator, operands[1].clone(), 1);
@@ -753,19 +866,28 @@ impl Instruction {
                     }
                 }
                 match (&operands[0], &operands[1]) {
-                    (&Register(Reg::A), &Register(Reg::R(r))) => Ok(Instruction::OrlAReg(r)),
-                    (&Register(Reg::A), &Direct(addr)) => Ok(Instruction::OrlADirect(addr)),
-                    (&Register(Reg::A), &IndirectReg(Reg::R(r))) if r < 2 => {
-                        Ok(Instruction::OrlAIndirReg(r))
+                    (Register(Reg::A), Register(Reg::R(r))) => Ok(Instruction::OrlAReg(*r)),
+                    (Register(Reg::A), Direct(addr)) => {
+                        Ok(Instruction::OrlADirect(addr.clone()))
+                    }
+                    (Register(Reg::A), IndirectReg(Reg::R(r))) if *r < 2 => {
+                        Ok(Instruction::OrlAIndirReg(*r))
                     }
-                    (&Register(Reg::A), &Immediate(imm)) if imm >= -128 && imm <= 255 => {
-                        Ok(Instruction::OrlAData(imm as u8))
+                    (Register(Reg::A), Immediate(imm)) => {
+                        Ok(Instruction::OrlAData(imm.clone()))
                     }
-                    (&Direct(addr), &Register(Reg::A)) => Ok(Instruction::OrlDirectA(addr)),
-                    (&Direct(addr), &Immediate(imm)) if imm >= -128 && imm <= 255 => {
-                        Ok(Instruction::OrlDirectData(addr, imm as u8))
+                    (Direct(addr), Register(Reg::A)) => {
+                        Ok(Instruction::OrlDirectA(addr.clone()))
+                    }
+                    (Direct(addr), Immediate(imm)) => {
+                        Ok(Instruction::OrlDirectData(addr.clone(), imm.clone()))
+                    }
+                    (Register(Reg::C), Direct(addr)) => {
+                        Ok(Instruction::OrlCBit(addr.clone()))
+                    }
+                    (Register(Reg::C), ComplementedBit(addr)) => {
+                        Ok(Instruction::OrlCNegBit(addr.clone()))
                     }
-                    (&Register(Reg::C), &Direct(addr)) => Ok(Instruction::OrlCBit(addr)),
                     _ => {
                         return Self::invalid_operand(operator, operands[1].clone(), 1);
                     }
@@ -774,7 +896,7 @@ impl Instruction {
             Operator::Pop => {
                 Self::expect_operands(operator, &operands, 1)?;
                 match operands[0] {
-                    Direct(addr) => Ok(Instruction::PopDirect(addr)),
+                    Direct(ref addr) => Ok(Instruction::PopDirect(addr.clone())),
                     _ => {
                         return Self::invalid_operand(operator, operands[0].clone(), 0);
                     }
@@ -783,7 +905,7 @@ impl Instruction {
             Operator::Push => {
                 Self::expect_operands(operator, &operands, 1)?;
                 match operands[0] {
-                    Direct(addr) => Ok(Instruction::PushDirect(addr)),
+                    Direct(ref addr) => Ok(Instruction::PushDirect(addr.clone())),
                     _ => {
                         return Self::invalid_operand(operator, operands[0].clone(), 0);
                     }
@@ -837,7 +959,7 @@ impl Instruction {
                 Self::expect_operands(operator, &operands, 1)?;
                 match operands[0] {
                     Register(Reg::C) => Ok(Instruction::SetbC),
-                    Direct(addr) => Ok(Instruction::SetbBit(addr)),
+                    Direct(ref addr) => Ok(Instruction::SetbBit(addr.clone())),
                     _ => {
                         return Self::invalid_operand(operator, operands[0].clone(), 0);
                     }
@@ -846,7 +968,7 @@ impl Instruction {
             Operator::Sjmp => {
                 Self::expect_operands(operator, &operands, 1)?;
                 match operands[0] {
-                    ImmediateId(ref addr) => Ok(Instruction::Sjmp(Address::Label(addr.clone()))),
+                    Address(ref addr) => Ok(Instruction::Sjmp(addr.clone())),
                     _ => {
                         return Self::invalid_operand(operator, operands[0].clone(), 0);
                     }
@@ -859,10 +981,10 @@ impl Instruction {
                 }
                 match operands[1] {
                     Register(Reg::R(r)) => Ok(Instruction::SubbAReg(r)),
-                    Direct(dir) => Ok(Instruction::SubbADirect(dir)),
+                    Direct(ref dir) => Ok(Instruction::SubbADirect(dir.clone())),
                     IndirectReg(Reg::R(r)) if r < 2 => Ok(Instruction::SubbAIndirReg(r)),
-                    Immediate(imm) if imm >= -128 && imm <= 255 => {
-                        Ok(Instruction::SubbAData(imm as u8))
+                    Immediate(ref imm) => {
+                        Ok(Instruction::SubbAData(imm.clone()))
                     }
                     _ => {
                         return Self::invalid_operand(operator, operands[1].clone(), 1);
@@ -885,7 +1007,7 @@ impl Instruction {
                 }
                 match operands[1] {
                     Register(Reg::R(r)) => Ok(Instruction::XchAReg(r)),
-                    Direct(dir) => Ok(Instruction::XchADirect(dir)),
+                    Direct(ref dir) => Ok(Instruction::XchADirect(dir.clone())),
                     IndirectReg(Reg::R(r)) if r < 2 => Ok(Instruction::XchAIndirReg(r)),
                     _ => {
                         return Self::invalid_operand(operator, operands[1].clone(), 1);
@@ -915,17 +1037,21 @@ impl Instruction {
                     }
                 }
                 match (&operands[0], &operands[1]) {
-                    (&Register(Reg::A), &Register(Reg::R(r))) => Ok(Instruction::XrlAReg(r)),
-                    (&Register(Reg::A), &Direct(addr)) => Ok(Instruction::XrlADirect(addr)),
-                    (&Register(Reg::A), &IndirectReg(Reg::R(r))) if r < 2 => {
-                        Ok(Instruction::XrlAIndirReg(r))
+                    (Register(Reg::A), Register(Reg::R(r))) => Ok(Instruction::XrlAReg(*r)),
+                    (Register(Reg::A), Direct(addr)) => {
+                        Ok(Instruction::XrlADirect(addr.clone()))
                     }
-                    (&Register(Reg::A), &Immediate(imm)) if imm >= -128 && imm <= 255 => {
-                        Ok(Instruction::XrlAData(imm as u8))
+                    (Register(Reg::A), IndirectReg(Reg::R(r))) if *r < 2 => {
+                        Ok(Instruction::XrlAIndirReg(*r))
                     }
-                    (&Direct(addr), &Register(Reg::A)) => Ok(Instruction::XrlDirectA(addr)),
-                    (&Direct(addr), &Immediate(imm)) if imm >= -128 && imm <= 255 => {
-                        Ok(Instruction::XrlDirectData(addr, imm as u8))
+                    (Register(Reg::A), Immediate(imm)) => {
+                        Ok(Instruction::XrlAData(imm.clone()))
+                    }
+                    (Direct(addr), Register(Reg::A)) => {
+                        Ok(Instruction::XrlDirectA(addr.clone()))
+                    }
+                    (Direct(addr), Immediate(imm)) => {
+                        Ok(Instruction::XrlDirectData(addr.clone(), imm.clone()))
                     }
                     _ => {
                         return Self::invalid_operand(operator, operands[1].clone(), 1);
@@ -935,132 +1061,386 @@ impl Instruction {
         }
     }
 
+    /// Returns whether the direct operand of the instruction is a bit address
+    fn addresses_bit(&self) -> bool {
+        matches!(*self,
+                 Instruction::AnlCBit(_) | Instruction::AnlCNegBit(_) | Instruction::ClrBit(_) |
+                 Instruction::CplBit(_) | Instruction::JbBitRel(_, _) |
+                 Instruction::JbcBitRel(_, _) | Instruction::JnbBitRel(_, _) |
+                 Instruction::MovCBit(_) | Instruction::MovBitC(_) | Instruction::OrlCBit(_) |
+                 Instruction::OrlCNegBit(_) | Instruction::SetbBit(_))
+    }
+
+    /// Returns the target of a relative branch
+    pub fn relative_target(&self) -> Option<&Expr> {
+        match *self {
+            Instruction::CjneADirRel(_, ref addr) |
+            Instruction::CjneADataRel(_, ref addr) |
+            Instruction::CJneRegDataRel(_, _, ref addr) |
+            Instruction::CjneIndirRegDataRel(_, _, ref addr) |
+            Instruction::DjnzRegRel(_, ref addr) |
+            Instruction::DjnzDirectRel(_, ref addr) |
+            Instruction::JbBitRel(_, ref addr) |
+            Instruction::JbcBitRel(_, ref addr) |
+            Instruction::JcRel(ref addr) |
+            Instruction::Jmp(ref addr, JumpForm::Short) |
+            Instruction::JnbBitRel(_, ref addr) |
+            Instruction::JncRel(ref addr) |
+            Instruction::JnzRel(ref addr) |
+            Instruction::JzRel(ref addr) |
+            Instruction::Sjmp(ref addr) => Some(addr),
+            _ => None,
+        }
+    }
+
+    /// Returns the target of an ACALL or AJMP, which has to be within the current 2 KB page
+    pub fn absolute_target(&self) -> Option<&Expr> {
+        match *self {
+            Instruction::Acall(ref addr) |
+            Instruction::Ajmp(ref addr) |
+            Instruction::Call(ref addr, JumpForm::Absolute) |
+            Instruction::Jmp(ref addr, JumpForm::Absolute) => Some(addr),
+            _ => None,
+        }
+    }
+
+    /// Returns the long form of a conditional jump, or `None` for other instructions
+    pub fn expanded(&self) -> Option<Instruction> {
+        if self.retarget(Expr::Number(0)).is_some() {
+            Some(Instruction::LongBranch(Box::new(self.clone())))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the conditional jump with the opposite condition, jumping to `target`
+    fn invert(&self, target: Expr) -> Option<Instruction> {
+        match *self {
+            Instruction::JbBitRel(ref bit, _) => Some(Instruction::JnbBitRel(bit.clone(), target)),
+            Instruction::JcRel(_) => Some(Instruction::JncRel(target)),
+            Instruction::JnbBitRel(ref bit, _) => Some(Instruction::JbBitRel(bit.clone(), target)),
+            Instruction::JncRel(_) => Some(Instruction::JcRel(target)),
+            Instruction::JnzRel(_) => Some(Instruction::JzRel(target)),
+            Instruction::JzRel(_) => Some(Instruction::JnzRel(target)),
+            _ => None,
+        }
+    }
+
+    /// Returns the same conditional jump, jumping to `target`
+    fn retarget(&self, target: Expr) -> Option<Instruction> {
+        match *self {
+            Instruction::CjneADirRel(ref dir, _) => {
+                Some(Instruction::CjneADirRel(dir.clone(), target))
+            }
+            Instruction::CjneADataRel(ref data, _) => {
+                Some(Instruction::CjneADataRel(data.clone(), target))
+            }
+            Instruction::CJneRegDataRel(r, ref data, _) => {
+                Some(Instruction::CJneRegDataRel(r, data.clone(), target))
+            }
+            Instruction::CjneIndirRegDataRel(r, ref data, _) => {
+                Some(Instruction::CjneIndirRegDataRel(r, data.clone(), target))
+            }
+            Instruction::DjnzRegRel(r, _) => Some(Instruction::DjnzRegRel(r, target)),
+            Instruction::DjnzDirectRel(ref dir, _) => {
+                Some(Instruction::DjnzDirectRel(dir.clone(), target))
+            }
+            Instruction::JbBitRel(ref bit, _) => Some(Instruction::JbBitRel(bit.clone(), target)),
+            Instruction::JbcBitRel(ref bit, _) => Some(Instruction::JbcBitRel(bit.clone(), target)),
+            Instruction::JcRel(_) => Some(Instruction::JcRel(target)),
+            Instruction::JnbBitRel(ref bit, _) => Some(Instruction::JnbBitRel(bit.clone(), target)),
+            Instruction::JncRel(_) => Some(Instruction::JncRel(target)),
+            Instruction::JnzRel(_) => Some(Instruction::JnzRel(target)),
+            Instruction::JzRel(_) => Some(Instruction::JzRel(target)),
+            _ => None,
+        }
+    }
+
+    /// The instructions that the long form of a conditional jump at `cur_addr` consists of.
+    /// If the condition can be inverted, the inverted branch skips over an LJMP to the target.
+    /// Otherwise the branch jumps to the LJMP and an SJMP skips over it.
+    fn expansion(&self, cur_addr: u16) -> Vec<Instruction> {
+        let target = match self.relative_target() {
+            Some(target) => target.clone(),
+            None => return vec![],
+        };
+        let next_addr = cur_addr as i32 + self.bytes() as i32;
+        if let Some(inverted) = self.invert(Expr::Number(next_addr + 3)) {
+            return vec![inverted, Instruction::Ljmp(target)];
+        }
+        match self.retarget(Expr::Number(next_addr + 2)) {
+            Some(branch) => {
+                vec![branch,
+                     Instruction::Sjmp(Expr::Number(next_addr + 5)),
+                     Instruction::Ljmp(target)]
+            }
+            None => vec![],
+        }
+    }
+
+    /// Picks the shortest encoding of a generic CALL or JMP that reaches its target from
+    /// `cur_addr`. The form only ever grows, so that repeated layout passes terminate. Returns
+    /// whether the instruction has grown.
+    pub fn select_form(&mut self, symbols: &HashMap<String, i32>, cur_addr: u16) -> bool {
+        let (addr, form, min_form) = match *self {
+            Instruction::Call(ref addr, ref mut form) => (addr, form, JumpForm::Absolute),
+            Instruction::Jmp(ref addr, ref mut form) => (addr, form, JumpForm::Short),
+            _ => return false,
+        };
+        let target = match Self::eval(addr, symbols) {
+            Ok(target) => target,
+            Err(_) => return false,
+        };
+        let next_addr = cur_addr as i32 + 2;
+        let new_form = if min_form == JumpForm::Short &&
+                          (-128..=127).contains(&(target - next_addr)) {
+            JumpForm::Short
+        } else if (0..=65535).contains(&target) && target & 0xF800 == next_addr & 0xF800 {
+            JumpForm::Absolute
+        } else {
+            JumpForm::Long
+        };
+        if new_form > *form {
+            *form = new_form;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn eval(expr: &Expr, symbols: &HashMap<String, i32>) -> Result<i32, InstructionError> {
+        Ok(expr.eval(&|s| symbols.get(s).cloned())?)
+    }
+
+    fn in_range(expr: &Expr,
+                symbols: &HashMap<String, i32>,
+                min: i32,
+                max: i32)
+                -> Result<i32, InstructionError> {
+        let value = Self::eval(expr, symbols)?;
+        if (min..=max).contains(&value) {
+            Ok(value)
+        } else {
+            Err(InstructionError::ValueOutOfRange(value))
+        }
+    }
+
+    /// Evaluates a direct address, a bit address or a byte of data
+    fn byte(expr: &Expr, symbols: &HashMap<String, i32>) -> Result<u8, InstructionError> {
+        Ok(Self::in_range(expr, symbols, 0, 255)? as u8)
+    }
+
+    /// Evaluates a bit address, which only has 256 values
+    fn bit(expr: &Expr, symbols: &HashMap<String, i32>) -> Result<u8, InstructionError> {
+        let value = Self::eval(expr, symbols)?;
+        if (0..=255).contains(&value) {
+            Ok(value as u8)
+        } else {
+            Err(InstructionError::InvalidBitAddress(value))
+        }
+    }
+
+    /// Evaluates immediate data - negative values are stored in two's complement
+    fn data(expr: &Expr, symbols: &HashMap<String, i32>) -> Result<u8, InstructionError> {
+        Ok(Self::in_range(expr, symbols, -128, 255)? as u8)
+    }
+
+    /// Evaluates a 16-bit word, such as a code address
+    fn word(expr: &Expr, symbols: &HashMap<String, i32>) -> Result<u16, InstructionError> {
+        Ok(Self::in_range(expr, symbols, 0, 65535)? as u16)
+    }
+
+    /// Evaluates the target of an ACALL or AJMP, which must be in the same 2 KB page as
+    /// `next_addr`
+    fn absolute(expr: &Expr,
+                symbols: &HashMap<String, i32>,
+                next_addr: i32)
+                -> Result<u16, InstructionError> {
+        let addr = Self::word(expr, symbols)?;
+        if addr as i32 & 0xF800 == next_addr & 0xF800 {
+            Ok(addr)
+        } else {
+            Err(InstructionError::ValueOutOfRange(addr as i32))
+        }
+    }
+
+    /// Evaluates the target of a relative branch as an offset from `next_addr`
+    fn relative(expr: &Expr,
+                symbols: &HashMap<String, i32>,
+                next_addr: i32)
+                -> Result<u8, InstructionError> {
+        let offset = Self::eval(expr, symbols)? - next_addr;
+        if (-128..=127).contains(&offset) {
+            Ok(offset as u8)
+        } else {
+            Err(InstructionError::ValueOutOfRange(offset))
+        }
+    }
+
     pub fn to_bytes(&self,
-                    labels: &HashMap<String, u16>,
+                    symbols: &HashMap<String, i32>,
                     cur_addr: u16)
                     -> Result<Vec<u8>, InstructionError> {
         match *self {
             Instruction::Acall(ref addr) => {
-                let addr = addr.to_u16(labels)?;
+                let addr = Self::absolute(addr, symbols, cur_addr as i32 + 2)?;
                 Ok(vec![((addr >> 3) & 0xE0) as u8 | 0x11, (addr & 0xFF) as u8])
             }
             Instruction::AddAReg(r) => Ok(vec![0x28 | r]),
-            Instruction::AddADirect(dir) => Ok(vec![0x25, dir]),
+            Instruction::AddADirect(ref dir) => Ok(vec![0x25, Self::byte(dir, symbols)?]),
             Instruction::AddAIndirReg(r) => Ok(vec![0x26 | r]),
-            Instruction::AddAData(data) => Ok(vec![0x24, data]),
+            Instruction::AddAData(ref data) => Ok(vec![0x24, Self::data(data, symbols)?]),
             Instruction::AddcAReg(r) => Ok(vec![0x38 | r]),
-            Instruction::AddcADirect(dir) => Ok(vec![0x35, dir]),
+            Instruction::AddcADirect(ref dir) => Ok(vec![0x35, Self::byte(dir, symbols)?]),
             Instruction::AddcAIndirReg(r) => Ok(vec![0x36 | r]),
-            Instruction::AddcAData(data) => Ok(vec![0x34, data]),
+            Instruction::AddcAData(ref data) => Ok(vec![0x34, Self::data(data, symbols)?]),
             Instruction::Ajmp(ref addr) => {
-                let addr = addr.to_u16(labels)?;
+                let addr = Self::absolute(addr, symbols, cur_addr as i32 + 2)?;
                 Ok(vec![((addr >> 3) & 0xE0) as u8 | 0x01, (addr & 0xFF) as u8])
             }
             Instruction::AnlAReg(r) => Ok(vec![0x58 | r]),
-            Instruction::AnlADirect(dir) => Ok(vec![0x55, dir]),
+            Instruction::AnlADirect(ref dir) => Ok(vec![0x55, Self::byte(dir, symbols)?]),
             Instruction::AnlAIndirReg(r) => Ok(vec![0x56 | r]),
-            Instruction::AnlAData(data) => Ok(vec![0x54, data]),
-            Instruction::AnlDirectA(dir) => Ok(vec![0x52, dir]),
-            Instruction::AnlDirectData(dir, data) => Ok(vec![0x53, dir, data]),
-            Instruction::AnlCBit(bit) => Ok(vec![0x82, bit]),
-            Instruction::AnlCNegBit(bit) => Ok(vec![0xB0, bit]),
-            Instruction::CjneADirRel(dir, ref addr) => {
-                let addr = addr.to_u16(labels)?.wrapping_sub(cur_addr + 3);
-                Ok(vec![0xB5, dir, addr as u8])
-            }
-            Instruction::CjneADataRel(data, ref addr) => {
-                let addr = addr.to_u16(labels)?.wrapping_sub(cur_addr + 3);
-                Ok(vec![0xB4, data, addr as u8])
-            }
-            Instruction::CJneRegDataRel(r, data, ref addr) => {
-                let addr = addr.to_u16(labels)?.wrapping_sub(cur_addr + 3);
-                Ok(vec![0xB8 | r, data, addr as u8])
-            }
-            Instruction::CjneIndirRegDataRel(r, data, ref addr) => {
-                let addr = addr.to_u16(labels)?.wrapping_sub(cur_addr + 3);
-                Ok(vec![0xB6 | r, data, addr as u8])
+            Instruction::AnlAData(ref data) => Ok(vec![0x54, Self::data(data, symbols)?]),
+            Instruction::AnlDirectA(ref dir) => Ok(vec![0x52, Self::byte(dir, symbols)?]),
+            Instruction::AnlDirectData(ref dir, ref data) => {
+                Ok(vec![0x53, Self::byte(dir, symbols)?, Self::data(data, symbols)?])
+            }
+            Instruction::AnlCBit(ref bit) => Ok(vec![0x82, Self::bit(bit, symbols)?]),
+            Instruction::AnlCNegBit(ref bit) => Ok(vec![0xB0, Self::bit(bit, symbols)?]),
+            Instruction::Call(ref addr, JumpForm::Long) => {
+                Instruction::Lcall(addr.clone()).to_bytes(symbols, cur_addr)
+            }
+            Instruction::Call(ref addr, _) => {
+                Instruction::Acall(addr.clone()).to_bytes(symbols, cur_addr)
+            }
+            Instruction::CjneADirRel(ref dir, ref addr) => {
+                let addr = Self::relative(addr, symbols, cur_addr as i32 + 3)?;
+                Ok(vec![0xB5, Self::byte(dir, symbols)?, addr])
+            }
+            Instruction::CjneADataRel(ref data, ref addr) => {
+                let addr = Self::relative(addr, symbols, cur_addr as i32 + 3)?;
+                Ok(vec![0xB4, Self::data(data, symbols)?, addr])
+            }
+            Instruction::CJneRegDataRel(r, ref data, ref addr) => {
+                let addr = Self::relative(addr, symbols, cur_addr as i32 + 3)?;
+                Ok(vec![0xB8 | r, Self::data(data, symbols)?, addr])
+            }
+            Instruction::CjneIndirRegDataRel(r, ref data, ref addr) => {
+                let addr = Self::relative(addr, symbols, cur_addr as i32 + 3)?;
+                Ok(vec![0xB6 | r, Self::data(data, symbols)?, addr])
             }
             Instruction::ClrA => Ok(vec![0xE4]),
             Instruction::ClrC => Ok(vec![0xC3]),
-            Instruction::ClrBit(bit) => Ok(vec![0xC2, bit]),
+            Instruction::ClrBit(ref bit) => Ok(vec![0xC2, Self::bit(bit, symbols)?]),
             Instruction::CplA => Ok(vec![0xF4]),
             Instruction::CplC => Ok(vec![0xB3]),
-            Instruction::CplBit(bit) => Ok(vec![0xB2, bit]),
+            Instruction::CplBit(ref bit) => Ok(vec![0xB2, Self::bit(bit, symbols)?]),
             Instruction::DaA => Ok(vec![0xD4]),
             Instruction::DecA => Ok(vec![0x14]),
             Instruction::DecReg(r) => Ok(vec![0x18 | r]),
-            Instruction::DecDirect(dir) => Ok(vec![0x15, dir]),
+            Instruction::DecDirect(ref dir) => Ok(vec![0x15, Self::byte(dir, symbols)?]),
             Instruction::DecIndirReg(r) => Ok(vec![0x16 | r]),
             Instruction::DivAB => Ok(vec![0x84]),
             Instruction::DjnzRegRel(r, ref addr) => {
-                let addr = addr.to_u16(labels)?.wrapping_sub(cur_addr + 2);
-                Ok(vec![0xD8 | r, addr as u8])
+                let addr = Self::relative(addr, symbols, cur_addr as i32 + 2)?;
+                Ok(vec![0xD8 | r, addr])
             }
-            Instruction::DjnzDirectRel(dir, ref addr) => {
-                let addr = addr.to_u16(labels)?.wrapping_sub(cur_addr + 3);
-                Ok(vec![0xD5, dir, addr as u8])
+            Instruction::DjnzDirectRel(ref dir, ref addr) => {
+                let addr = Self::relative(addr, symbols, cur_addr as i32 + 3)?;
+                Ok(vec![0xD5, Self::byte(dir, symbols)?, addr])
             }
             Instruction::IncA => Ok(vec![0x04]),
             Instruction::IncReg(r) => Ok(vec![0x08 | r]),
-            Instruction::IncDirect(dir) => Ok(vec![0x05, dir]),
+            Instruction::IncDirect(ref dir) => Ok(vec![0x05, Self::byte(dir, symbols)?]),
             Instruction::IncIndirReg(r) => Ok(vec![0x06 | r]),
             Instruction::IncDptr => Ok(vec![0xA3]),
-            Instruction::JbBitRel(bit, ref addr) => {
-                let addr = addr.to_u16(labels)?.wrapping_sub(cur_addr + 3);
-                Ok(vec![0x20, bit, addr as u8])
+            Instruction::JbBitRel(ref bit, ref addr) => {
+                let addr = Self::relative(addr, symbols, cur_addr as i32 + 3)?;
+                Ok(vec![0x20, Self::bit(bit, symbols)?, addr])
             }
-            Instruction::JbcBitRel(bit, ref addr) => {
-                let addr = addr.to_u16(labels)?.wrapping_sub(cur_addr + 3);
-                Ok(vec![0x10, bit, addr as u8])
+            Instruction::JbcBitRel(ref bit, ref addr) => {
+                let addr = Self::relative(addr, symbols, cur_addr as i32 + 3)?;
+                Ok(vec![0x10, Self::bit(bit, symbols)?, addr])
             }
             Instruction::JcRel(ref addr) => {
-                let addr = addr.to_u16(labels)?.wrapping_sub(cur_addr + 2);
-                Ok(vec![0x40, addr as u8])
+                let addr = Self::relative(addr, symbols, cur_addr as i32 + 2)?;
+                Ok(vec![0x40, addr])
+            }
+            Instruction::Jmp(ref addr, JumpForm::Short) => {
+                Instruction::Sjmp(addr.clone()).to_bytes(symbols, cur_addr)
+            }
+            Instruction::Jmp(ref addr, JumpForm::Absolute) => {
+                Instruction::Ajmp(addr.clone()).to_bytes(symbols, cur_addr)
+            }
+            Instruction::Jmp(ref addr, JumpForm::Long) => {
+                Instruction::Ljmp(addr.clone()).to_bytes(symbols, cur_addr)
             }
             Instruction::JmpIndirAPlusDptr => Ok(vec![0x73]),
-            Instruction::JnbBitRel(bit, ref addr) => {
-                let addr = addr.to_u16(labels)?.wrapping_sub(cur_addr + 3);
-                Ok(vec![0x30, bit, addr as u8])
+            Instruction::JnbBitRel(ref bit, ref addr) => {
+                let addr = Self::relative(addr, symbols, cur_addr as i32 + 3)?;
+                Ok(vec![0x30, Self::bit(bit, symbols)?, addr])
             }
             Instruction::JncRel(ref addr) => {
-                let addr = addr.to_u16(labels)?.wrapping_sub(cur_addr + 2);
-                Ok(vec![0x50, addr as u8])
+                let addr = Self::relative(addr, symbols, cur_addr as i32 + 2)?;
+                Ok(vec![0x50, addr])
             }
             Instruction::JnzRel(ref addr) => {
-                let addr = addr.to_u16(labels)?.wrapping_sub(cur_addr + 2);
-                Ok(vec![0x70, addr as u8])
+                let addr = Self::relative(addr, symbols, cur_addr as i32 + 2)?;
+                Ok(vec![0x70, addr])
             }
             Instruction::JzRel(ref addr) => {
-                let addr = addr.to_u16(labels)?.wrapping_sub(cur_addr + 2);
-                Ok(vec![0x60, addr as u8])
+                let addr = Self::relative(addr, symbols, cur_addr as i32 + 2)?;
+                Ok(vec![0x60, addr])
             }
             Instruction::Lcall(ref addr) => {
-                let addr = addr.to_u16(labels)?;
+                let addr = Self::word(addr, symbols)?;
                 Ok(vec![0x12, (addr / 256) as u8, (addr % 256) as u8])
             }
             Instruction::Ljmp(ref addr) => {
-                let addr = addr.to_u16(labels)?;
+                let addr = Self::word(addr, symbols)?;
                 Ok(vec![0x02, (addr / 256) as u8, (addr % 256) as u8])
             }
+            Instruction::LongBranch(ref branch) => {
+                let mut bytes = vec![];
+                let mut addr = cur_addr;
+                for instruction in branch.expansion(cur_addr) {
+                    bytes.extend(instruction.to_bytes(symbols, addr)?);
+                    // the layout keeps the code below 10000h, which only the address after
+                    // the last instruction can reach
+                    addr = addr.wrapping_add(instruction.bytes());
+                }
+                Ok(bytes)
+            }
             Instruction::MovAReg(r) => Ok(vec![0xE8 | r]),
-            Instruction::MovADirect(dir) => Ok(vec![0xE5, dir]),
+            Instruction::MovADirect(ref dir) => Ok(vec![0xE5, Self::byte(dir, symbols)?]),
             Instruction::MovAIndirReg(r) => Ok(vec![0xE6 | r]),
-            Instruction::MovAData(data) => Ok(vec![0x74, data]),
+            Instruction::MovAData(ref data) => Ok(vec![0x74, Self::data(data, symbols)?]),
             Instruction::MovRegA(r) => Ok(vec![0xF8 | r]),
-            Instruction::MovRegDir(r, dir) => Ok(vec![0xC8 | r, dir]),
-            Instruction::MovRegData(r, data) => Ok(vec![0x78 | r, data]),
-            Instruction::MovDirectA(dir) => Ok(vec![0xF5, dir]),
-            Instruction::MovDirectReg(dir, r) => Ok(vec![0x88 | r, dir]),
-            Instruction::MovDirectDirect(dir, dir2) => Ok(vec![0x85, dir2, dir]),
-            Instruction::MovDirectIndirReg(dir, r) => Ok(vec![0x86 | r, dir]),
-            Instruction::MovDirectData(dir, data) => Ok(vec![0x75, dir, data]),
+            Instruction::MovRegDir(r, ref dir) => Ok(vec![0xA8 | r, Self::byte(dir, symbols)?]),
+            Instruction::MovRegData(r, ref data) => Ok(vec![0x78 | r, Self::data(data, symbols)?]),
+            Instruction::MovDirectA(ref dir) => Ok(vec![0xF5, Self::byte(dir, symbols)?]),
+            Instruction::MovDirectReg(ref dir, r) => Ok(vec![0x88 | r, Self::byte(dir, symbols)?]),
+            Instruction::MovDirectDirect(ref dir, ref dir2) => {
+                Ok(vec![0x85, Self::byte(dir2, symbols)?, Self::byte(dir, symbols)?])
+            }
+            Instruction::MovDirectIndirReg(ref dir, r) => {
+                Ok(vec![0x86 | r, Self::byte(dir, symbols)?])
+            }
+            Instruction::MovDirectData(ref dir, ref data) => {
+                Ok(vec![0x75, Self::byte(dir, symbols)?, Self::data(data, symbols)?])
+            }
             Instruction::MovIndirRegA(r) => Ok(vec![0xF6 | r]),
-            Instruction::MovIndirRegDirect(r, dir) => Ok(vec![0xA6 | r, dir]),
-            Instruction::MovIndirRegData(r, data) => Ok(vec![0x76 | r, data]),
-            Instruction::MovCBit(bit) => Ok(vec![0xA2, bit]),
-            Instruction::MovBitC(bit) => Ok(vec![0x92, bit]),
+            Instruction::MovIndirRegDirect(r, ref dir) => {
+                Ok(vec![0xA6 | r, Self::byte(dir, symbols)?])
+            }
+            Instruction::MovIndirRegData(r, ref data) => {
+                Ok(vec![0x76 | r, Self::data(data, symbols)?])
+            }
+            Instruction::MovCBit(ref bit) => Ok(vec![0xA2, Self::bit(bit, symbols)?]),
+            Instruction::MovBitC(ref bit) => Ok(vec![0x92, Self::bit(bit, symbols)?]),
             Instruction::MovDptrData(ref addr) => {
-                let addr = addr.to_u16(labels)?;
+                let addr = Self::word(addr, symbols)?;
                 Ok(vec![0x90, (addr / 256) as u8, (addr % 256) as u8])
             }
             Instruction::MovcAIndirAPlusDptr => Ok(vec![0x93]),
@@ -1072,15 +1452,17 @@ impl Instruction {
             Instruction::MulAB => Ok(vec![0xA4]),
             Instruction::Nop => Ok(vec![0x00]),
             Instruction::OrlAReg(r) => Ok(vec![0x48 | r]),
-            Instruction::OrlADirect(dir) => Ok(vec![0x45, dir]),
+            Instruction::OrlADirect(ref dir) => Ok(vec![0x45, Self::byte(dir, symbols)?]),
             Instruction::OrlAIndirReg(r) => Ok(vec![0x46 | r]),
-            Instruction::OrlAData(data) => Ok(vec![0x44, data]),
-            Instruction::OrlDirectA(dir) => Ok(vec![0x42, dir]),
-            Instruction::OrlDirectData(dir, data) => Ok(vec![0x43, dir, data]),
-            Instruction::OrlCBit(bit) => Ok(vec![0x72, bit]),
-            Instruction::OrlCNegBit(bit) => Ok(vec![0xA0, bit]),
-            Instruction::PopDirect(dir) => Ok(vec![0xD0, dir]),
-            Instruction::PushDirect(dir) => Ok(vec![0xC0, dir]),
+            Instruction::OrlAData(ref data) => Ok(vec![0x44, Self::data(data, symbols)?]),
+            Instruction::OrlDirectA(ref dir) => Ok(vec![0x42, Self::byte(dir, symbols)?]),
+            Instruction::OrlDirectData(ref dir, ref data) => {
+                Ok(vec![0x43, Self::byte(dir, symbols)?, Self::data(data, symbols)?])
+            }
+            Instruction::OrlCBit(ref bit) => Ok(vec![0x72, Self::bit(bit, symbols)?]),
+            Instruction::OrlCNegBit(ref bit) => Ok(vec![0xA0, Self::bit(bit, symbols)?]),
+            Instruction::PopDirect(ref dir) => Ok(vec![0xD0, Self::byte(dir, symbols)?]),
+            Instruction::PushDirect(ref dir) => Ok(vec![0xC0, Self::byte(dir, symbols)?]),
             Instruction::Ret => Ok(vec![0x22]),
             Instruction::Reti => Ok(vec![0x32]),
             Instruction::RlA => Ok(vec![0x23]),
@@ -1088,27 +1470,42 @@ impl Instruction {
             Instruction::RrA => Ok(vec![0x03]),
             Instruction::RrcA => Ok(vec![0x13]),
             Instruction::SetbC => Ok(vec![0xD3]),
-            Instruction::SetbBit(bit) => Ok(vec![0xD2, bit]),
+            Instruction::SetbBit(ref bit) => Ok(vec![0xD2, Self::bit(bit, symbols)?]),
             Instruction::Sjmp(ref addr) => {
-                let addr = addr.to_u16(labels)?.wrapping_sub(cur_addr + 2);
-                Ok(vec![0x80, addr as u8])
+                let addr = Self::relative(addr, symbols, cur_addr as i32 + 2)?;
+                Ok(vec![0x80, addr])
             }
             Instruction::SubbAReg(r) => Ok(vec![0x98 | r]),
-            Instruction::SubbADirect(dir) => Ok(vec![0x95, dir]),
+            Instruction::SubbADirect(ref dir) => Ok(vec![0x95, Self::byte(dir, symbols)?]),
             Instruction::SubbAIndirReg(r) => Ok(vec![0x96 | r]),
-            Instruction::SubbAData(data) => Ok(vec![0x94, data]),
+            Instruction::SubbAData(ref data) => Ok(vec![0x94, Self::data(data, symbols)?]),
             Instruction::SwapA => Ok(vec![0xC4]),
             Instruction::XchAReg(r) => Ok(vec![0xC8 | r]),
-            Instruction::XchADirect(dir) => Ok(vec![0xC5, dir]),
+            Instruction::XchADirect(ref dir) => Ok(vec![0xC5, Self::byte(dir, symbols)?]),
             Instruction::XchAIndirReg(r) => Ok(vec![0xC6 | r]),
             Instruction::XchdAIndirReg(r) => Ok(vec![0xD6 | r]),
             Instruction::XrlAReg(r) => Ok(vec![0x68 | r]),
-            Instruction::XrlADirect(dir) => Ok(vec![0x65, dir]),
+            Instruction::XrlADirect(ref dir) => Ok(vec![0x65, Self::byte(dir, symbols)?]),
             Instruction::XrlAIndirReg(r) => Ok(vec![0x66 | r]),
-            Instruction::XrlAData(data) => Ok(vec![0x64, data]),
-            Instruction::XrlDirectA(dir) => Ok(vec![0x62, dir]),
-            Instruction::XrlDirectData(dir, data) => Ok(vec![0x63, dir, data]),
-            Instruction::Bytes(ref b) => Ok(b.clone()),
+            Instruction::XrlAData(ref data) => Ok(vec![0x64, Self::data(data, symbols)?]),
+            Instruction::XrlDirectA(ref dir) => Ok(vec![0x62, Self::byte(dir, symbols)?]),
+            Instruction::XrlDirectData(ref dir, ref data) => {
+                Ok(vec![0x63, Self::byte(dir, symbols)?, Self::data(data, symbols)?])
+            }
+            Instruction::Data(ref values) => {
+                let mut bytes = vec![];
+                for value in values {
+                    match *value {
+                        Value::Byte(ref byte) => bytes.push(Self::byte(byte, symbols)?),
+                        Value::Word(ref word) => {
+                            let word = Self::word(word, symbols)?;
+                            bytes.extend(&[(word % 256) as u8, (word / 256) as u8]);
+                        }
+                        Value::String(ref s) => bytes.extend(s),
+                    }
+                }
+                Ok(bytes)
+            }
         }
     }
 }
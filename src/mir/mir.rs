@@ -1,60 +1,459 @@
 use super::{Instruction, InstructionError};
-use parser::ast::{Line, LineBody, Program};
-use std::collections::HashMap;
+use parser::ast::{Line, LineBody, Program, SourceSpan};
+use parser::expr::{EvalError, Expr};
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Display};
 
 #[derive(Debug)]
 pub struct Mir {
-    labels: HashMap<String, u16>,
-    equ_defs: HashMap<String, i32>,
-    instructions: Vec<(u16, Instruction)>,
+    symbols: HashMap<String, i32>,
+    instructions: Vec<Code>,
+    /// The way each symbol is first written in the source, by its name in the symbol table
+    spellings: HashMap<String, String>,
+}
+
+/// An instruction placed at its address, along with where in the source it comes from
+#[derive(Debug)]
+struct Code {
+    addr: u16,
+    instruction: Instruction,
+    span: SourceSpan,
+}
+
+/// An error in generating the code for a line of the program
+#[derive(Clone, Debug)]
+pub struct CodeError {
+    pub span: SourceSpan,
+    pub error: InstructionError,
+}
+
+impl Display for CodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.span, self.error)
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Options {
+    /// Rewrite conditional jumps whose targets are out of reach into long branches
+    pub expand_branches: bool,
+    /// Tell apart user symbols that differ only in case; otherwise they are all uppercased
+    pub case_sensitive: bool,
+}
+
+/// An EQU definition: the name of the symbol, its value and the line that defines it
+type EquDef = (String, Expr, SourceSpan);
+
+/// The addresses of the labels, and the address of every statement
+type Layout = (HashMap<String, i32>, Vec<u16>);
+
+/// A line of the program, as far as laying out the code is concerned
+enum Statement {
+    /// A new address, along with the line that sets it
+    Org(Expr, SourceSpan),
+    Label(String),
+    /// An instruction or a data definition, along with the part of the source it comes from
+    Code(Instruction, SourceSpan),
 }
 
 impl Mir {
-    pub fn from_program(program: Program) -> Result<Self, InstructionError> {
-        let mut labels = HashMap::new();
-        let mut equ_defs = HashMap::new();
-        let mut instructions = Vec::new();
-        let mut current_address = 0;
-        for line in program.lines.into_iter() {
-            let (label, body) = match line {
+    pub fn from_program(program: Program, options: &Options) -> Result<Self, Vec<CodeError>> {
+        let spellings = RefCell::new(HashMap::new());
+        let result = Self::build(program, options, &spellings);
+        let spellings = spellings.into_inner();
+        match result {
+            Ok(mir) => Ok(Mir { spellings, ..mir }),
+            Err(errors) => {
+                Err(errors
+                        .into_iter()
+                        .map(|e| Self::respell(e, &spellings))
+                        .collect())
+            }
+        }
+    }
+
+    fn build(program: Program,
+             options: &Options,
+             spellings: &RefCell<HashMap<String, String>>)
+             -> Result<Self, Vec<CodeError>> {
+        let mut names = HashSet::new();
+        let mut equ_defs = Vec::new();
+        let mut statements = Vec::new();
+        let mut errors = Vec::new();
+        let name = |s: &str| {
+            let name = Self::symbol_name(s, options);
+            spellings
+                .borrow_mut()
+                .entry(name.clone())
+                .or_insert_with(|| s.to_owned());
+            name
+        };
+        for line in program.lines {
+            // `$` is bound to a label of its own at the start of the line, which no user symbol
+            // can clash with, since `$` can't be a part of a name
+            let here = format!("${}", statements.len());
+            let uses_location = Cell::new(false);
+            let locate = |s: &str| if s == "$" {
+                uses_location.set(true);
+                here.clone()
+            } else {
+                name(s)
+            };
+
+            let (label, body) = match line.node {
                 Line::OrgLine { address } => {
-                    current_address = address;
+                    let address = address.rename_symbols(&locate);
+                    if uses_location.get() {
+                        statements.push(Statement::Label(here.clone()));
+                    }
+                    statements.push(Statement::Org(address, line.span));
                     continue;
                 }
                 Line::EquDef { id, value } => {
-                    equ_defs.insert(id, value);
+                    let id = name(&id);
+                    if let Err(error) = Self::check_new_symbol(&id, &mut names) {
+                        errors.push(CodeError {
+                                        span: line.span,
+                                        error,
+                                    });
+                        continue;
+                    }
+                    equ_defs.push((id, value.rename_symbols(&locate), line.span));
+                    if uses_location.get() {
+                        statements.push(Statement::Label(here.clone()));
+                    }
                     continue;
                 }
                 Line::ProgramLine { label, body } => (label, body),
             };
             if let Some(label) = label {
-                labels.insert(label.0, current_address);
+                let name = name(&label.node.0);
+                match Self::check_new_symbol(&name, &mut names) {
+                    Ok(()) => statements.push(Statement::Label(name)),
+                    Err(error) => {
+                        errors.push(CodeError {
+                                        span: label.span,
+                                        error,
+                                    })
+                    }
+                }
             }
             if let Some(body) = body {
-                match body {
+                // an invalid operand is reported at the operand rather than at the whole line
+                let mut operand_spans = Vec::new();
+                let instruction = match body.node {
                     LineBody::CodeLine { operator, operands } => {
-                        let instruction = Instruction::from_code(operator, operands)?;
-                        let len = instruction.bytes();
-                        instructions.push((current_address, instruction));
-                        current_address += len;
+                        let operands = operands
+                            .into_iter()
+                            .map(|op| {
+                                     operand_spans.push(op.span);
+                                     op.node.rename_symbols(&locate)
+                                 })
+                            .collect();
+                        Instruction::from_code(operator, operands)
                     }
                     LineBody::ValueDefinition { values } => {
-                        let mut bytes = vec![];
-                        for value in values {
-                            bytes.extend(value.into_bytes());
+                        Ok(Instruction::Data(values
+                                                 .into_iter()
+                                                 .map(|v| v.node.rename_symbols(&locate))
+                                                 .collect()))
+                    }
+                };
+                match instruction {
+                    Ok(instruction) => {
+                        if uses_location.get() {
+                            statements.push(Statement::Label(here.clone()));
                         }
-                        let len = bytes.len() as u16;
-                        instructions.push((current_address, Instruction::Bytes(bytes)));
-                        current_address += len;
+                        statements.push(Statement::Code(instruction, body.span));
+                    }
+                    Err(error) => {
+                        let span = match error {
+                            InstructionError::InvalidOperand { pos, .. } => {
+                                operand_spans[pos as usize].clone()
+                            }
+                            _ => body.span,
+                        };
+                        errors.push(CodeError { span, error })
+                    }
+                }
+            }
+        }
+        // the layout can't be trusted with lines missing, so it's only done for a valid program
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+
+        // Generic jumps start out in their shortest form and grow until they reach their
+        // targets, and so do the conditional jumps if they are to be expanded. Every time one
+        // of them grows, the code after it moves, so the layout has to be redone until nothing
+        // changes anymore.
+        loop {
+            let (labels, addresses) = Self::layout(&statements, &equ_defs)?;
+            let symbols = Self::resolve_symbols(&labels, &equ_defs, false)?;
+            let mut changed = false;
+            for (statement, &addr) in statements.iter_mut().zip(addresses.iter()) {
+                if let Statement::Code(ref mut instruction, _) = *statement {
+                    changed |= instruction.select_form(&symbols, addr);
+                    if options.expand_branches {
+                        changed |= Self::expand_branch(instruction, &symbols, addr);
+                    }
+                }
+            }
+            if !changed {
+                let symbols = Self::resolve_symbols(&labels, &equ_defs, true)?;
+                let mut instructions = Vec::new();
+                for (statement, addr) in statements.into_iter().zip(addresses) {
+                    if let Statement::Code(instruction, span) = statement {
+                        instructions.push(Code {
+                                              addr,
+                                              instruction,
+                                              span,
+                                          });
                     }
                 }
+                return Ok(Mir {
+                              symbols,
+                              instructions,
+                              spellings: HashMap::new(),
+                          });
             }
         }
-        Ok(Mir {
-               labels: labels,
-               equ_defs: equ_defs,
-               instructions: instructions,
-           })
+    }
+
+    /// Returns the addresses and the source of the conditional jumps that have been rewritten
+    /// into long branches
+    pub fn expanded_branches(&self) -> Vec<(u16, &SourceSpan)> {
+        self.instructions
+            .iter()
+            .filter(|code| matches!(code.instruction, Instruction::LongBranch(_)))
+            .map(|code| (code.addr, &code.span))
+            .collect()
+    }
+
+    /// Returns the part of the source that the code at the given address has been generated
+    /// from
+    pub fn source_span(&self, addr: u16) -> Option<&SourceSpan> {
+        self.instructions
+            .iter()
+            .find(|code| {
+                      (code.addr as u32..code.addr as u32 + code.instruction.bytes() as u32)
+                          .contains(&(addr as u32))
+                  })
+            .map(|code| &code.span)
+    }
+
+    /// Replaces a conditional jump with its long form if its target is out of reach.
+    /// Returns whether the instruction has been replaced.
+    fn expand_branch(instruction: &mut Instruction,
+                     symbols: &HashMap<String, i32>,
+                     addr: u16)
+                     -> bool {
+        match Self::branch_excess(instruction, symbols, addr) {
+            Ok(Some((_, excess))) if excess > 0 => (),
+            _ => return false,
+        }
+        match instruction.expanded() {
+            Some(expanded) => {
+                *instruction = expanded;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the target of a relative branch and by how many bytes it is out of reach,
+    /// or `None` if the instruction isn't a relative branch
+    fn branch_excess(instruction: &Instruction,
+                     symbols: &HashMap<String, i32>,
+                     addr: u16)
+                     -> Result<Option<(i32, i32)>, InstructionError> {
+        let target = match instruction.relative_target() {
+            Some(target) => target.eval(&|s| symbols.get(s).cloned())?,
+            None => return Ok(None),
+        };
+        let offset = target - (addr as i32 + instruction.bytes() as i32);
+        let excess = if offset > 127 { offset - 127 } else { -128 - offset };
+        Ok(Some((target, excess)))
+    }
+
+    /// The name under which a user symbol goes into the symbol table
+    fn symbol_name(s: &str, options: &Options) -> String {
+        if options.case_sensitive {
+            s.to_owned()
+        } else {
+            s.to_uppercase()
+        }
+    }
+
+    /// Puts back the spelling from the source into the symbol names that an error mentions,
+    /// since the symbol table only has them uppercased if case doesn't matter
+    fn respell(error: CodeError, spellings: &HashMap<String, String>) -> CodeError {
+        let CodeError { span, error } = error;
+        let spell = |s: String| spellings.get(&s).cloned().unwrap_or(s);
+        let error = match error {
+            InstructionError::UnknownLabel(s) => InstructionError::UnknownLabel(spell(s)),
+            InstructionError::DuplicateSymbol(s) => InstructionError::DuplicateSymbol(spell(s)),
+            InstructionError::CircularDefinition(s) => {
+                InstructionError::CircularDefinition(spell(s))
+            }
+            other => other,
+        };
+        CodeError { span, error }
+    }
+
+    fn check_new_symbol(id: &str, names: &mut HashSet<String>) -> Result<(), InstructionError> {
+        if names.insert(id.to_owned()) {
+            Ok(())
+        } else {
+            Err(InstructionError::DuplicateSymbol(id.to_owned()))
+        }
+    }
+
+    /// Assigns addresses to the statements, using the current sizes of the instructions.
+    /// Returns the addresses of the labels and of every statement.
+    fn layout(statements: &[Statement], equ_defs: &[EquDef]) -> Result<Layout, Vec<CodeError>> {
+        let mut labels = HashMap::new();
+        let mut addresses = Vec::with_capacity(statements.len());
+        // counted past FFFFh, so that code reaching the end of the memory can be told apart
+        // from code running over it
+        let mut current_address: i32 = 0;
+        for statement in statements {
+            let size = match *statement {
+                Statement::Org(ref address, ref span) => {
+                    let symbols = Self::resolve_symbols(&labels, equ_defs, false)?;
+                    let address = match address.eval(&|s| symbols.get(s).cloned()) {
+                        Ok(address) if (0..=65535).contains(&address) => address,
+                        Ok(address) => {
+                            return Err(vec![CodeError {
+                                                span: span.clone(),
+                                                error: InstructionError::ValueOutOfRange(address),
+                                            }])
+                        }
+                        Err(e) => {
+                            return Err(vec![CodeError {
+                                                span: span.clone(),
+                                                error: e.into(),
+                                            }])
+                        }
+                    };
+                    current_address = address;
+                    0
+                }
+                Statement::Label(ref label) => {
+                    labels.insert(label.clone(), current_address);
+                    0
+                }
+                Statement::Code(ref instruction, ref span) => {
+                    let end = current_address + instruction.bytes() as i32;
+                    if end > 0x10000 {
+                        return Err(vec![CodeError {
+                                            span: span.clone(),
+                                            error: InstructionError::AddressOutOfRange(end),
+                                        }]);
+                    }
+                    instruction.bytes()
+                }
+            };
+            // only the code needs an address, and it always starts below 10000h
+            addresses.push(current_address as u16);
+            current_address += size as i32;
+        }
+        Ok((labels, addresses))
+    }
+
+    /// Makes sure that the target of a relative branch, an ACALL or an AJMP is within its reach
+    fn check_target(instruction: &Instruction,
+                    symbols: &HashMap<String, i32>,
+                    addr: u16)
+                    -> Result<(), InstructionError> {
+        if let Some((target, excess)) = Self::branch_excess(instruction, symbols, addr)? {
+            if excess > 0 {
+                return Err(InstructionError::BranchOutOfRange {
+                               instruction: instruction.clone(),
+                               target,
+                               excess,
+                           });
+            }
+        }
+        if let Some(target) = instruction.absolute_target() {
+            let target = target.eval(&|s| symbols.get(s).cloned())?;
+            let page = (addr as i32 + instruction.bytes() as i32) & 0xF800;
+            if target & 0xF800 != page {
+                return Err(InstructionError::TargetOutsidePage {
+                               instruction: instruction.clone(),
+                               target,
+                               page,
+                           });
+            }
+        }
+        Ok(())
+    }
+
+    /// Builds the symbol table out of the labels and the EQU definitions.
+    ///
+    /// EQU values may refer to labels and to other EQU symbols, in any order, so they are
+    /// evaluated repeatedly until no more of them can be resolved. If `strict` is false, the
+    /// symbols that can't be resolved yet are just left out of the table.
+    fn resolve_symbols(labels: &HashMap<String, i32>,
+                       equ_defs: &[EquDef],
+                       strict: bool)
+                       -> Result<HashMap<String, i32>, Vec<CodeError>> {
+        let mut symbols: HashMap<String, i32> = labels
+            .iter()
+            .map(|(label, &addr)| (label.clone(), addr))
+            .collect();
+        let mut pending: Vec<&EquDef> = equ_defs.iter().collect();
+
+        loop {
+            let num_pending = pending.len();
+            let mut unresolved = Vec::new();
+            for equ in pending {
+                let value = equ.1.eval(&|s| symbols.get(s).cloned());
+                match value {
+                    Ok(value) => {
+                        symbols.insert(equ.0.clone(), value);
+                    }
+                    Err(_) => unresolved.push(equ),
+                }
+            }
+            pending = unresolved;
+            if pending.is_empty() || pending.len() == num_pending {
+                break;
+            }
+        }
+
+        if !strict {
+            return Ok(symbols);
+        }
+
+        // report the definitions that fail for a reason other than depending on another
+        // unresolved EQU; if there are none, the remaining EQUs form a cycle
+        let mut errors = Vec::new();
+        for equ in pending.iter() {
+            match equ.1.eval(&|s| symbols.get(s).cloned()) {
+                Err(EvalError::UnknownSymbol(ref s)) if pending.iter().any(|equ| &equ.0 == s) => (),
+                Err(e) => {
+                    errors.push(CodeError {
+                                    span: equ.2.clone(),
+                                    error: e.into(),
+                                })
+                }
+                Ok(_) => unreachable!(),
+            }
+        }
+        if errors.is_empty() {
+            match pending.first() {
+                Some(equ) => {
+                    errors.push(CodeError {
+                                    span: equ.2.clone(),
+                                    error: InstructionError::CircularDefinition(equ.0.clone()),
+                                })
+                }
+                None => return Ok(symbols),
+            }
+        }
+        Err(errors)
     }
 
     fn intel_hex(addr: u16, bytes: Vec<u8>) -> String {
@@ -70,15 +469,771 @@ impl Mir {
         result
     }
 
-    pub fn gen_intel_hex(&self) -> String {
+    fn encode(&self, code: &Code) -> Result<Vec<u8>, InstructionError> {
+        Self::check_target(&code.instruction, &self.symbols, code.addr)?;
+        code.instruction.to_bytes(&self.symbols, code.addr)
+    }
+
+    /// Generates the Intel HEX file, or returns the errors in all the lines that couldn't be
+    /// encoded
+    pub fn gen_intel_hex(&self) -> Result<String, Vec<CodeError>> {
         let mut result = String::new();
-        for &(addr, ref instruction) in self.instructions.iter() {
-            let bytes = instruction
-                .to_bytes(&self.labels, addr)
-                .unwrap_or_else(|e| panic!("ERROR: {:?}", e));
-            result.push_str(&Self::intel_hex(addr, bytes));
+        let mut errors = Vec::new();
+        for code in self.instructions.iter() {
+            match self.encode(code) {
+                Ok(bytes) => result.push_str(&Self::intel_hex(code.addr, bytes)),
+                Err(error) => {
+                    errors.push(Self::respell(CodeError {
+                                                  span: code.span.clone(),
+                                                  error,
+                                              },
+                                              &self.spellings))
+                }
+            }
+        }
+        if !errors.is_empty() {
+            return Err(errors);
         }
         result.push_str(":00000001FF");
-        result
+        Ok(result)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use parser::ast::{Operand, ParserState};
+    use parser::keywords::{Operator, Register};
+    use parser::lexer::Tokenizer;
+
+    fn mir(text: &str) -> Result<Mir, Vec<CodeError>> {
+        mir_with_options(text, &Options::default())
+    }
+
+    fn mir_with_options(text: &str, options: &Options) -> Result<Mir, Vec<CodeError>> {
+        let tokens = Tokenizer::tokenize(text).unwrap();
+        let program = ParserState::parse(tokens, "test.asm").unwrap();
+        Mir::from_program(program, options)
+    }
+
+    /// A generated table file of about 75 KB, along with the number of bytes it assembles to
+    fn large_table() -> (String, usize) {
+        let mut text = String::from("org 100h\nstart: mov dptr, #table\n");
+        let mut size = 3;
+        for i in 0..1000 {
+            text.push_str(&format!("entry{}: db {}h, 0{:X}h, %1010, 'x' ; entry {}\n\
+                                    mov a, #(entry{} - table) AND 0FFh\n",
+                                   i,
+                                   i % 10,
+                                   i % 256,
+                                   i,
+                                   i));
+            size += 6;
+        }
+        text.push_str("table: dw start, 0FFFFh\n");
+        size += 4;
+        (text, size)
+    }
+
+    #[test]
+    fn test_large_table() {
+        let (text, size) = large_table();
+        assert!(text.len() > 60 * 1024);
+        let mir = mir(&text).unwrap();
+        let code_size: usize = encode(&mir).iter().map(|(_, bytes)| bytes.len()).sum();
+        assert_eq!(code_size, size);
+    }
+
+    /// Keeps the assembler from getting slow on large files again; the bound is far above what
+    /// it takes even in a debug build, so that only a blowup like reparsing every line trips it
+    #[test]
+    fn test_large_table_time() {
+        use std::time::{Duration, Instant};
+        let (text, _) = large_table();
+        let start = Instant::now();
+        let mir = mir(&text).unwrap();
+        mir.gen_intel_hex().unwrap();
+        assert!(start.elapsed() < Duration::from_secs(3),
+                "{} KB assembled in {:?}",
+                text.len() / 1024,
+                start.elapsed());
+    }
+
+    fn assemble(text: &str) -> Vec<(u16, Vec<u8>)> {
+        encode(&mir(text).unwrap())
+    }
+
+    fn encode(mir: &Mir) -> Vec<(u16, Vec<u8>)> {
+        mir.instructions
+            .iter()
+            .map(|code| (code.addr, mir.encode(code).unwrap()))
+            .collect()
+    }
+
+    fn errors(text: &str) -> Vec<CodeError> {
+        mir(text).unwrap().gen_intel_hex().unwrap_err()
+    }
+
+    #[test]
+    fn test_expressions() {
+        let program = "org 8 * 2\n\
+                       start: mov a, #(20 - 1) * 2\n\
+                       mov dptr, #table + 2 * 3\n\
+                       mov 20h + 1, #-1\n\
+                       setb 20h.(1 + 2)\n\
+                       table: db 1, 2 + 3\n\
+                       dw table, 1000h - 1";
+        assert_eq!(assemble(program),
+                   vec![(0x10, vec![0x74, 0x26]),
+                        (0x12, vec![0x90, 0x00, 0x20]),
+                        (0x15, vec![0x75, 0x21, 0xFF]),
+                        (0x18, vec![0xD2, 0x03]),
+                        (0x1A, vec![0x01, 0x05]),
+                        (0x1C, vec![0x1A, 0x00, 0xFF, 0x0F])]);
+    }
+
+    /// Every mnemonic with every form of operands it takes, each assembled on its own at
+    /// address 0, so branches to 10h are 0Eh or 0Dh bytes ahead of the next instruction
+    #[test]
+    fn test_instruction_set() {
+        let fixed: &[(&str, &[u8])] = &[("nop", &[0x00]),
+                                         ("ajmp 123h", &[0x21, 0x23]),
+                                         ("ajmp 7FFh", &[0xE1, 0xFF]),
+                                         ("ljmp 1234h", &[0x02, 0x12, 0x34]),
+                                         ("rr a", &[0x03]),
+                                         ("inc a", &[0x04]),
+                                         ("inc 30h", &[0x05, 0x30]),
+                                         ("jbc 20h.1, 10h", &[0x10, 0x01, 0x0D]),
+                                         ("acall 123h", &[0x31, 0x23]),
+                                         ("acall 7FFh", &[0xF1, 0xFF]),
+                                         ("lcall 1234h", &[0x12, 0x12, 0x34]),
+                                         ("rrc a", &[0x13]),
+                                         ("dec a", &[0x14]),
+                                         ("dec 30h", &[0x15, 0x30]),
+                                         ("jb 20h.1, 10h", &[0x20, 0x01, 0x0D]),
+                                         ("ret", &[0x22]),
+                                         ("rl a", &[0x23]),
+                                         ("add a, #12h", &[0x24, 0x12]),
+                                         ("add a, 30h", &[0x25, 0x30]),
+                                         ("jnb 20h.1, 10h", &[0x30, 0x01, 0x0D]),
+                                         ("reti", &[0x32]),
+                                         ("rlc a", &[0x33]),
+                                         ("addc a, #12h", &[0x34, 0x12]),
+                                         ("addc a, 30h", &[0x35, 0x30]),
+                                         ("jc 10h", &[0x40, 0x0E]),
+                                         ("orl 30h, a", &[0x42, 0x30]),
+                                         ("orl 30h, #12h", &[0x43, 0x30, 0x12]),
+                                         ("orl a, #12h", &[0x44, 0x12]),
+                                         ("orl a, 30h", &[0x45, 0x30]),
+                                         ("jnc 10h", &[0x50, 0x0E]),
+                                         ("anl 30h, a", &[0x52, 0x30]),
+                                         ("anl 30h, #12h", &[0x53, 0x30, 0x12]),
+                                         ("anl a, #12h", &[0x54, 0x12]),
+                                         ("anl a, 30h", &[0x55, 0x30]),
+                                         ("jz 10h", &[0x60, 0x0E]),
+                                         ("xrl 30h, a", &[0x62, 0x30]),
+                                         ("xrl 30h, #12h", &[0x63, 0x30, 0x12]),
+                                         ("xrl a, #12h", &[0x64, 0x12]),
+                                         ("xrl a, 30h", &[0x65, 0x30]),
+                                         ("jnz 10h", &[0x70, 0x0E]),
+                                         ("orl c, 20h.1", &[0x72, 0x01]),
+                                         ("jmp @a+dptr", &[0x73]),
+                                         ("mov a, #12h", &[0x74, 0x12]),
+                                         ("mov 30h, #12h", &[0x75, 0x30, 0x12]),
+                                         ("sjmp 10h", &[0x80, 0x0E]),
+                                         ("anl c, 20h.1", &[0x82, 0x01]),
+                                         ("movc a, @a+pc", &[0x83]),
+                                         ("div ab", &[0x84]),
+                                         ("div a, b", &[0x84]),
+                                         ("mov 30h, 31h", &[0x85, 0x31, 0x30]),
+                                         ("mov dptr, #1234h", &[0x90, 0x12, 0x34]),
+                                         ("mov 20h.1, c", &[0x92, 0x01]),
+                                         ("movc a, @a+dptr", &[0x93]),
+                                         ("subb a, #12h", &[0x94, 0x12]),
+                                         ("subb a, 30h", &[0x95, 0x30]),
+                                         ("orl c, /20h.1", &[0xA0, 0x01]),
+                                         ("mov c, 20h.1", &[0xA2, 0x01]),
+                                         ("inc dptr", &[0xA3]),
+                                         ("mul ab", &[0xA4]),
+                                         ("mul a, b", &[0xA4]),
+                                         ("anl c, /20h.1", &[0xB0, 0x01]),
+                                         ("cpl 20h.1", &[0xB2, 0x01]),
+                                         ("cpl c", &[0xB3]),
+                                         ("cjne a, #12h, 10h", &[0xB4, 0x12, 0x0D]),
+                                         ("cjne a, 30h, 10h", &[0xB5, 0x30, 0x0D]),
+                                         ("push 30h", &[0xC0, 0x30]),
+                                         ("clr 20h.1", &[0xC2, 0x01]),
+                                         ("clr c", &[0xC3]),
+                                         ("swap a", &[0xC4]),
+                                         ("xch a, 30h", &[0xC5, 0x30]),
+                                         ("pop 30h", &[0xD0, 0x30]),
+                                         ("setb 20h.1", &[0xD2, 0x01]),
+                                         ("setb c", &[0xD3]),
+                                         ("da a", &[0xD4]),
+                                         ("djnz 30h, 10h", &[0xD5, 0x30, 0x0D]),
+                                         ("movx a, @dptr", &[0xE0]),
+                                         ("clr a", &[0xE4]),
+                                         ("mov a, 30h", &[0xE5, 0x30]),
+                                         ("movx @dptr, a", &[0xF0]),
+                                         ("cpl a", &[0xF4]),
+                                         ("mov 30h, a", &[0xF5, 0x30])];
+        let mut cases: Vec<(String, Vec<u8>)> = fixed.iter()
+            .map(|&(text, bytes)| (text.to_owned(), bytes.to_vec()))
+            .collect();
+        for i in 0..2 {
+            cases.extend(vec![(format!("inc @r{}", i), vec![0x06 + i]),
+                              (format!("dec @r{}", i), vec![0x16 + i]),
+                              (format!("add a, @r{}", i), vec![0x26 + i]),
+                              (format!("addc a, @r{}", i), vec![0x36 + i]),
+                              (format!("orl a, @r{}", i), vec![0x46 + i]),
+                              (format!("anl a, @r{}", i), vec![0x56 + i]),
+                              (format!("xrl a, @r{}", i), vec![0x66 + i]),
+                              (format!("mov @r{}, #12h", i), vec![0x76 + i, 0x12]),
+                              (format!("mov 30h, @r{}", i), vec![0x86 + i, 0x30]),
+                              (format!("subb a, @r{}", i), vec![0x96 + i]),
+                              (format!("mov @r{}, 30h", i), vec![0xA6 + i, 0x30]),
+                              (format!("cjne @r{}, #12h, 10h", i), vec![0xB6 + i, 0x12, 0x0D]),
+                              (format!("xch a, @r{}", i), vec![0xC6 + i]),
+                              (format!("xchd a, @r{}", i), vec![0xD6 + i]),
+                              (format!("movx a, @r{}", i), vec![0xE2 + i]),
+                              (format!("mov a, @r{}", i), vec![0xE6 + i]),
+                              (format!("movx @r{}, a", i), vec![0xF2 + i]),
+                              (format!("mov @r{}, a", i), vec![0xF6 + i])]);
+        }
+        for r in 0..8 {
+            cases.extend(vec![(format!("inc r{}", r), vec![0x08 + r]),
+                              (format!("dec r{}", r), vec![0x18 + r]),
+                              (format!("add a, r{}", r), vec![0x28 + r]),
+                              (format!("addc a, r{}", r), vec![0x38 + r]),
+                              (format!("orl a, r{}", r), vec![0x48 + r]),
+                              (format!("anl a, r{}", r), vec![0x58 + r]),
+                              (format!("xrl a, r{}", r), vec![0x68 + r]),
+                              (format!("mov r{}, #12h", r), vec![0x78 + r, 0x12]),
+                              (format!("mov 30h, r{}", r), vec![0x88 + r, 0x30]),
+                              (format!("subb a, r{}", r), vec![0x98 + r]),
+                              (format!("mov r{}, 30h", r), vec![0xA8 + r, 0x30]),
+                              (format!("cjne r{}, #12h, 10h", r), vec![0xB8 + r, 0x12, 0x0D]),
+                              (format!("xch a, r{}", r), vec![0xC8 + r]),
+                              (format!("djnz r{}, 10h", r), vec![0xD8 + r, 0x0E]),
+                              (format!("mov a, r{}", r), vec![0xE8 + r]),
+                              (format!("mov r{}, a", r), vec![0xF8 + r])]);
+        }
+
+        let failures: Vec<_> = cases.into_iter()
+            .filter_map(|(text, bytes)| {
+                let result = mir(&text).map_err(|e| format!("{:?}", e)).and_then(|mir| {
+                    mir.encode(&mir.instructions[0]).map_err(|e| format!("{:?}", e))
+                });
+                if result.as_ref() == Ok(&bytes) {
+                    None
+                } else {
+                    Some(format!("{}: expected {:02X?}, got {:02X?}", text, bytes, result))
+                }
+            })
+            .collect();
+        assert!(failures.is_empty(), "{}", failures.join("\n"));
+
+        // Only R0 and R1 can be used for indirect addressing, and it's the indirect operand
+        // that is blamed for it.
+        let mut rejected = Vec::new();
+        for r in 2..8 {
+            for form in &["inc @r{}", "dec @r{}", "mov a, @r{}", "mov @r{}, a", "mov @r{}, #1",
+                          "mov @r{}, 30h", "mov 30h, @r{}", "xch a, @r{}", "xchd a, @r{}",
+                          "movx a, @r{}", "movx @r{}, a", "cjne @r{}, #1, $", "add a, @r{}",
+                          "addc a, @r{}", "subb a, @r{}", "anl a, @r{}", "orl a, @r{}",
+                          "xrl a, @r{}"] {
+                rejected.push((form.replace("{}", &r.to_string()), r));
+            }
+        }
+        let accepted: Vec<_> = rejected.into_iter()
+            .filter(|&(ref text, r)| match mir(text) {
+                Err(ref errors) => !matches!(errors[..],
+                    [CodeError { error: InstructionError::InvalidOperand { ref operand, .. }, .. }]
+                        if *operand == Operand::IndirectReg(Register::R(r))),
+                Ok(_) => true,
+            })
+            .map(|(text, _)| text)
+            .collect();
+        assert!(accepted.is_empty(), "not rejected: {}", accepted.join(", "));
+    }
+
+    #[test]
+    fn test_expression_out_of_range() {
+        let mir = mir("mov a, #100h\n").unwrap();
+        assert!(mir.encode(&mir.instructions[0]).is_err());
+    }
+
+    #[test]
+    fn test_equ_operands() {
+        let program = "count equ 10\n\
+                       flags equ 20h\n\
+                       reset equ 100h\n\
+                       org reset\n\
+                       mov a, #count\n\
+                       mov flags, a\n\
+                       setb flags.1\n\
+                       ljmp entry\n\
+                       db count, size\n\
+                       entry equ start + 2\n\
+                       size equ end - start\n\
+                       start: nop\n\
+                       nop\n\
+                       end:";
+        assert_eq!(assemble(program),
+                   vec![(0x100, vec![0x74, 0x0A]),
+                        (0x102, vec![0xF5, 0x20]),
+                        (0x104, vec![0xD2, 0x01]),
+                        (0x106, vec![0x02, 0x01, 0x0D]),
+                        (0x109, vec![0x0A, 0x02]),
+                        (0x10B, vec![0x00]),
+                        (0x10C, vec![0x00])]);
+    }
+
+    #[test]
+    fn test_symbolic_direct_operands() {
+        let program = "mov a, counter\n\
+                       inc counter + 1\n\
+                       mov r8, rx\n\
+                       setb flag\n\
+                       clr flags.7\n\
+                       mov c, flags.0\n\
+                       jnb flag, done\n\
+                       done: nop\n\
+                       counter equ 30h\n\
+                       flags equ 2Fh\n\
+                       flag equ flags.3\n\
+                       r8 equ 40h\n\
+                       rx equ 41h";
+        assert_eq!(assemble(program),
+                   vec![(0x00, vec![0xE5, 0x30]),
+                        (0x02, vec![0x05, 0x31]),
+                        (0x04, vec![0x85, 0x41, 0x40]),
+                        (0x07, vec![0xD2, 0x7B]),
+                        (0x09, vec![0xC2, 0x7F]),
+                        (0x0B, vec![0xA2, 0x78]),
+                        (0x0D, vec![0x30, 0x7B, 0x00]),
+                        (0x10, vec![0x00])]);
+    }
+
+    #[test]
+    fn test_sfr_bits() {
+        let program = "setb TR0\n\
+                       wait: jnb ti, wait\n\
+                       clr EAL\n\
+                       mov c, CY\n\
+                       cpl iex6";
+        assert_eq!(assemble(program),
+                   vec![(0x00, vec![0xD2, 0x8C]),
+                        (0x02, vec![0x30, 0x99, 0xFD]),
+                        (0x05, vec![0xC2, 0xAF]),
+                        (0x07, vec![0xA2, 0xD7]),
+                        (0x09, vec![0xB2, 0xC5])]);
+    }
+
+    #[test]
+    fn test_bits_as_bytes() {
+        for text in &["mov a, TI", "mov r0, CY", "push OV", "mov 30h, p1.2", "inc ACC.7"] {
+            match &mir_errors(text)[..] {
+                &[(1, InstructionError::InvalidOperand { .. })] => (),
+                other => panic!("{}: expected an invalid operand error, got {:?}", text, other),
+            }
+        }
+        // the generic names of the PSW and ADCON bits are left to the user
+        assert_eq!(assemble("p: nop\nclk equ 3\nmov a, clk\nsjmp p"),
+                   vec![(0x00, vec![0x00]), (0x01, vec![0xE5, 0x03]), (0x03, vec![0x80, 0xFB])]);
+    }
+
+    #[test]
+    fn test_location_counter() {
+        let program = "org 10h\n\
+                       sjmp $\n\
+                       wait: jnb TI, $\n\
+                       mov a, #$ AND 0FFh\n\
+                       msg: db 'hi', $\n\
+                       len equ $ - msg\n\
+                       org ($ + 0FFh) AND 0FF00h\n\
+                       dw $, len";
+        assert_eq!(assemble(program),
+                   vec![(0x10, vec![0x80, 0xFE]),
+                        (0x12, vec![0x30, 0x99, 0xFD]),
+                        (0x15, vec![0x74, 0x15]),
+                        (0x17, vec![0x68, 0x69, 0x17]),
+                        (0x100, vec![0x00, 0x01, 0x03, 0x00])]);
+    }
+
+    #[test]
+    fn test_branch_targets() {
+        let program = "start: sjmp next\n\
+                       next: djnz R2, start\n\
+                       cjne a, #10, next\n\
+                       jb p1.2, start + 1\n\
+                       acall start\n\
+                       lcall start\n\
+                       jmp @a+dptr";
+        assert_eq!(assemble(program),
+                   vec![(0x00, vec![0x80, 0x00]),
+                        (0x02, vec![0xDA, 0xFC]),
+                        (0x04, vec![0xB4, 0x0A, 0xFB]),
+                        (0x07, vec![0x20, 0x92, 0xF7]),
+                        (0x0A, vec![0x11, 0x00]),
+                        (0x0C, vec![0x12, 0x00, 0x00]),
+                        (0x0F, vec![0x73])]);
+    }
+
+    #[test]
+    fn test_branch_target_immediate() {
+        match &mir_errors("sjmp #start\nstart: nop")[..] {
+            &[(1, InstructionError::InvalidOperand { operator: Operator::Sjmp, pos: 0, .. })] => (),
+            other => panic!("Expected an invalid operand error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_generic_jumps() {
+        let program = "start: jmp near\n\
+                       near: call start\n\
+                       jmp far\n\
+                       call other\n\
+                       jmp other\n\
+                       org 200h\n\
+                       far: nop\n\
+                       org 1000h\n\
+                       other: nop";
+        assert_eq!(assemble(program),
+                   vec![(0x00, vec![0x80, 0x00]),
+                        (0x02, vec![0x11, 0x00]),
+                        (0x04, vec![0x41, 0x00]),
+                        (0x06, vec![0x12, 0x10, 0x00]),
+                        (0x09, vec![0x02, 0x10, 0x00]),
+                        (0x200, vec![0x00]),
+                        (0x1000, vec![0x00])]);
+    }
+
+    #[test]
+    fn test_generic_jumps_grow() {
+        // the second jump has to become an LJMP, which pushes `end` out of the range of the
+        // first one
+        let program = format!("jmp end\n\
+                               db \"{}\"\n\
+                               jmp far\n\
+                               end: nop\n\
+                               org 1000h\n\
+                               far: nop",
+                              "x".repeat(125));
+        let result = assemble(&program);
+        assert_eq!(result[0], (0x00, vec![0x01, 0x82]));
+        assert_eq!(result[2], (0x7F, vec![0x02, 0x10, 0x00]));
+        assert_eq!(result[3], (0x82, vec![0x00]));
+    }
+
+    #[test]
+    fn test_branch_in_range() {
+        let program = format!("start: jz end\n\
+                               db \"{}\"\n\
+                               end: djnz R0, start",
+                              "x".repeat(124));
+        let result = assemble(&program);
+        assert_eq!(result[0], (0x00, vec![0x60, 0x7C]));
+        assert_eq!(result[2], (0x7E, vec![0xD8, 0x80]));
+    }
+
+    #[test]
+    fn test_branch_out_of_range() {
+        let program = format!("jz end\n\
+                               db \"{}\"\n\
+                               end: nop",
+                              "x".repeat(130));
+        let jz_errors = errors(&program);
+        assert_eq!(jz_errors[0].span.row, 1);
+        match jz_errors[0].error {
+            InstructionError::BranchOutOfRange {
+                instruction: Instruction::JzRel(_),
+                target: 0x84,
+                excess: 3,
+            } => (),
+            ref other => panic!("Expected a branch out of range error, got {:?}", other),
+        }
+
+        let program = format!("start: db \"{}\"\n\
+                               sjmp start",
+                              "x".repeat(127));
+        let sjmp_errors = errors(&program);
+        assert_eq!(sjmp_errors[0].span.row, 2);
+        match sjmp_errors[0].error {
+            InstructionError::BranchOutOfRange {
+                instruction: Instruction::Sjmp(_),
+                target: 0,
+                excess: 1,
+            } => (),
+            ref other => panic!("Expected a branch out of range error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_expand_branches() {
+        let options = Options { expand_branches: true, ..Options::default() };
+        let program = format!("jz end\n\
+                               jc far\n\
+                               djnz R0, end\n\
+                               db \"{}\"\n\
+                               end: cjne a, #1, far\n\
+                               org 1000h\n\
+                               far: nop",
+                              "x".repeat(123));
+        let mir = mir_with_options(&program, &options).unwrap();
+        // expanding the JC pushes `end` out of the reach of the JZ
+        let expanded: Vec<_> = mir.expanded_branches()
+            .into_iter()
+            .map(|(addr, span)| (addr, span.row))
+            .collect();
+        assert_eq!(expanded, vec![(0x00, 1), (0x05, 2), (0x87, 5)]);
+        let result = encode(&mir);
+        assert_eq!(result[0], (0x00, vec![0x70, 0x03, 0x02, 0x00, 0x87]));
+        assert_eq!(result[1], (0x05, vec![0x50, 0x03, 0x02, 0x10, 0x00]));
+        assert_eq!(result[2], (0x0A, vec![0xD8, 0x7B]));
+        assert_eq!(result[4],
+                   (0x87, vec![0xB4, 0x01, 0x02, 0x80, 0x03, 0x02, 0x10, 0x00]));
+    }
+
+    #[test]
+    fn test_expand_branches_disabled() {
+        match errors("jc far\norg 1000h\nfar: nop")[0].error {
+            InstructionError::BranchOutOfRange { .. } => (),
+            ref other => panic!("Expected a branch out of range error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_error_messages() {
+        let messages: Vec<_> = mir("mov a, @r5\nnop\nsetb 80h.9\nx equ 1\nx equ 2")
+            .unwrap_err()
+            .iter()
+            .map(|e| e.to_string())
+            .collect();
+        assert_eq!(messages,
+                   vec!["test.asm:1:8: Invalid operand for MOV",
+                        "test.asm:5:1: Symbol x is already defined"]);
+        assert_eq!(errors("jz far\norg 90h\nfar: nop")[0].to_string(),
+                   "test.asm:1:1: Branch target 0090h is 15 bytes out of reach");
+    }
+
+    #[test]
+    fn test_end_of_memory() {
+        // code may fill the memory up to its very last byte
+        assert_eq!(assemble("org 0FFFDh\nljmp 0\nend:"),
+                   vec![(0xFFFD, vec![0x02, 0x00, 0x00])]);
+        assert_eq!(assemble("org 0FFFEh\nsjmp $"), vec![(0xFFFE, vec![0x80, 0xFE])]);
+        let options = Options { expand_branches: true, ..Options::default() };
+        let mir = mir_with_options("org 0FFFBh\njz 0", &options).unwrap();
+        assert_eq!(encode(&mir), vec![(0xFFFB, vec![0x70, 0x03, 0x02, 0x00, 0x00])]);
+
+        match &mir_errors("org 0FFFFh\nljmp 0")[..] {
+            &[(2, InstructionError::AddressOutOfRange(0x10002))] => (),
+            other => panic!("Expected an address out of range error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_absolute_page() {
+        let program = "org 7FEh\n\
+                       ajmp 0FFFh\n\
+                       org 0F00h\n\
+                       acall 800h\n\
+                       ljmp 0";
+        assert_eq!(assemble(program),
+                   vec![(0x7FE, vec![0xE1, 0xFF]),
+                        (0xF00, vec![0x11, 0x00]),
+                        (0xF02, vec![0x02, 0x00, 0x00])]);
+    }
+
+    #[test]
+    fn test_absolute_outside_page() {
+        let page_errors = errors("org 7FEh\najmp 7FDh");
+        assert_eq!(page_errors[0].span.row, 2);
+        match page_errors[0].error {
+            InstructionError::TargetOutsidePage {
+                instruction: Instruction::Ajmp(_),
+                target: 0x7FD,
+                page: 0x800,
+            } => (),
+            ref other => panic!("Expected a target outside page error, got {:?}", other),
+        }
+        let page_errors = errors("org 1000h\nacall start\nstart equ 800h");
+        assert_eq!(page_errors[0].span.row, 2);
+        match page_errors[0].error {
+            InstructionError::TargetOutsidePage {
+                instruction: Instruction::Acall(_),
+                target: 0x800,
+                page: 0x1000,
+            } => (),
+            ref other => panic!("Expected a target outside page error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_code_errors() {
+        let errors = errors("mov a, #100h\n\
+                             nop\n\
+                             label: ljmp missing\n\
+                             sjmp far\n\
+                             org 1000h\n\
+                             far: nop");
+        assert_eq!(errors.len(), 3);
+        assert_eq!(errors[0].span.row, 1);
+        assert_eq!(errors[0].span.to_string(), "test.asm:1:1");
+        match errors[0].error {
+            InstructionError::ValueOutOfRange(0x100) => (),
+            ref other => panic!("Expected a value out of range error, got {:?}", other),
+        }
+        assert_eq!(errors[1].span.row, 3);
+        assert_eq!(errors[1].span.column, 8);
+        assert_eq!(errors[1].span.length, 12);
+        match errors[1].error {
+            InstructionError::UnknownLabel(ref s) if s == "missing" => (),
+            ref other => panic!("Expected an unknown label error, got {:?}", other),
+        }
+        assert_eq!(errors[2].span.row, 4);
+        match errors[2].error {
+            InstructionError::BranchOutOfRange { .. } => (),
+            ref other => panic!("Expected a branch out of range error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_bit_errors() {
+        let errors = errors("setb sp.2\n\
+                             clr dpl.1\n\
+                             cpl flags.8\n\
+                             jb big, 0\n\
+                             mov c, negative\n\
+                             setb p1.7\n\
+                             flags equ 20h\n\
+                             big equ 100h\n\
+                             negative equ -1");
+        let errors: Vec<_> = errors.iter().map(|e| (e.span.row, e.error.clone())).collect();
+        match &errors[..] {
+            &[(1, InstructionError::InvalidBitAddress(0x81)),
+              (2, InstructionError::InvalidBitAddress(0x82)),
+              (3, InstructionError::InvalidBitNumber(8)),
+              (4, InstructionError::InvalidBitAddress(0x100)),
+              (5, InstructionError::InvalidBitAddress(-1))] => (),
+            other => panic!("Unexpected errors: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_source_span() {
+        let mir = mir("org 10h\n\
+                       start: mov a, #1\n\
+                       \tdb 1, 2, 3\n\
+                       ljmp start")
+                .unwrap();
+        let span = mir.source_span(0x11).unwrap();
+        assert_eq!((span.row, span.column, span.length), (2, 8, 9));
+        let span = mir.source_span(0x14).unwrap();
+        assert_eq!((&*span.file, span.row, span.column, span.length), ("test.asm", 3, 2, 10));
+        assert_eq!(mir.source_span(0x15).unwrap().row, 4);
+        assert_eq!(mir.source_span(0x0F), None);
+        assert_eq!(mir.source_span(0x18), None);
+    }
+
+    /// The errors in building the MIR, along with the rows they are reported at
+    fn mir_errors(text: &str) -> Vec<(usize, InstructionError)> {
+        mir(text).unwrap_err().into_iter().map(|e| (e.span.row, e.error)).collect()
+    }
+
+    #[test]
+    fn test_equ_duplicate() {
+        match &mir_errors("count equ 10\ncount equ 11")[..] {
+            &[(2, InstructionError::DuplicateSymbol(ref s))] if s == "count" => (),
+            other => panic!("Expected a duplicate symbol error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_equ_label_clash() {
+        match &mir_errors("start equ 10\nstart: nop")[..] {
+            &[(2, InstructionError::DuplicateSymbol(ref s))] if s == "start" => (),
+            other => panic!("Expected a duplicate symbol error, got {:?}", other),
+        }
+        match &mir_errors("start: nop\nstart equ 10")[..] {
+            &[(2, InstructionError::DuplicateSymbol(ref s))] if s == "start" => (),
+            other => panic!("Expected a duplicate symbol error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_equ_circular() {
+        match &mir_errors("a1 equ b1 + 1\nb1 equ a1 - 1")[..] {
+            &[(1, InstructionError::CircularDefinition(ref s))] if s == "a1" => (),
+            other => panic!("Expected a circular definition error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_mir_errors() {
+        let errors = mir_errors("mov r0, r1\n\
+                                 nop\n\
+                                 mov r2, r3\n\
+                                 count equ 1\n\
+                                 count: nop\n\
+                                 x equ missing\n\
+                                 y equ other");
+        match &errors[..] {
+            &[(1, InstructionError::InvalidOperand { .. }),
+              (3, InstructionError::InvalidOperand { .. }),
+              (5, InstructionError::DuplicateSymbol(_))] => (),
+            other => panic!("Unexpected errors: {:?}", other),
+        }
+
+        // an invalid operand points at the operand itself
+        let errors = mir("nop\nanl a, @r3").unwrap_err();
+        assert_eq!(errors[0].span.to_string(), "test.asm:2:8");
+        assert_eq!(errors[0].span.length, 3);
+
+        // once the lines are valid, every EQU that can't be resolved is reported
+        match &mir_errors("x equ missing\nnop\ny equ other")[..] {
+            &[(1, InstructionError::UnknownLabel(_)), (3, InstructionError::UnknownLabel(_))] => (),
+            other => panic!("Unexpected errors: {:?}", other),
+        }
+        match &mir_errors("nop\norg 10000h")[..] {
+            &[(2, InstructionError::ValueOutOfRange(0x10000))] => (),
+            other => panic!("Unexpected errors: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_symbol_case() {
+        let program = "Count equ 3\n\
+                       start: mov a, #COUNT\n\
+                       sjmp START";
+        assert_eq!(assemble(program),
+                   vec![(0x00, vec![0x74, 0x03]), (0x02, vec![0x80, 0xFC])]);
+
+        let options = Options { case_sensitive: true, ..Options::default() };
+        let sensitive_mir = mir_with_options(program, &options).unwrap();
+        assert_eq!(sensitive_mir.gen_intel_hex().unwrap_err().len(), 2);
+        match mir_with_options("start: nop\nSTART: nop", &options) {
+            Ok(_) => (),
+            Err(e) => panic!("Expected distinct symbols, got {:?}", e),
+        }
+        match &mir_errors("start: nop\nSTART: nop")[..] {
+            &[(2, InstructionError::DuplicateSymbol(ref s))] if s == "start" => (),
+            other => panic!("Expected a duplicate symbol error, got {:?}", other),
+        }
+        match errors("ljmp Missing\nljmp MISSING")[..] {
+            [CodeError { error: InstructionError::UnknownLabel(ref s1), .. },
+             CodeError { error: InstructionError::UnknownLabel(ref s2), .. }]
+                if s1 == "Missing" && s2 == "Missing" => (),
+            ref other => panic!("Expected unknown label errors, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_equ_unknown() {
+        match &mir_errors("a1 equ b1 + 1\nb1 equ missing")[..] {
+            &[(2, InstructionError::UnknownLabel(ref s))] if s == "missing" => (),
+            other => panic!("Expected an unknown label error, got {:?}", other),
+        }
     }
 }
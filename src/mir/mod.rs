@@ -1,5 +1,5 @@
 mod mir;
 mod instruction;
 
-pub use self::instruction::{Address, Instruction, InstructionError};
-pub use self::mir::Mir;
+pub use self::instruction::{Instruction, InstructionError};
+pub use self::mir::{CodeError, Mir, Options};
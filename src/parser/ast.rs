@@ -1,19 +1,43 @@
+use super::expr::{BinaryOperator, Expr, UnaryOperator};
 use super::keywords::{Definition, Keyword, Operator, Register};
 use super::lexer;
-use regex::Regex;
+use std::fmt::{self, Display};
+use std::rc::Rc;
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct Program {
-    pub lines: Vec<Line>,
+    pub lines: Vec<Located<Line>>,
+}
+
+/// A node of the syntax tree, along with the part of the source it was parsed from
+#[derive(Clone, Debug, PartialEq)]
+pub struct Located<T> {
+    pub node: T,
+    pub span: SourceSpan,
+}
+
+/// A part of a single line of the source
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceSpan {
+    pub file: Rc<str>,
+    pub row: usize,
+    pub column: usize,
+    pub length: usize,
+}
+
+impl Display for SourceSpan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.row, self.column)
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Line {
-    OrgLine { address: u16 },
-    EquDef { id: String, value: i32 },
+    OrgLine { address: Expr },
+    EquDef { id: String, value: Expr },
     ProgramLine {
-        label: Option<Label>,
-        body: Option<LineBody>,
+        label: Option<Located<Label>>,
+        body: Option<Located<LineBody>>,
     },
 }
 
@@ -21,9 +45,9 @@ pub enum Line {
 pub enum LineBody {
     CodeLine {
         operator: Operator,
-        operands: Vec<Operand>,
+        operands: Vec<Located<Operand>>,
     },
-    ValueDefinition { values: Vec<Value> },
+    ValueDefinition { values: Vec<Located<Value>> },
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -32,26 +56,57 @@ pub struct Label(pub String);
 #[derive(Clone, Debug, PartialEq)]
 pub enum Operand {
     Register(Register),
-    Direct(u8),
+    Direct(Expr),
     IndirectReg(Register),
     IndirectSum(Register, Register),
-    Immediate(i32),
-    ImmediateId(String),
+    Immediate(Expr),
+    /// A code address - the target of a jump or a call
+    Address(Expr),
+    /// `/bit` - the complement of a bit, read by `ANL C` and `ORL C`
+    ComplementedBit(Expr),
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum Value {
-    Byte(u8),
-    Word(u16),
-    String(String),
+    Byte(Expr),
+    Word(Expr),
+    String(Vec<u8>),
+}
+
+impl Operand {
+    /// Replaces the name of every symbol in the operand with `rename(name)`
+    pub fn rename_symbols<F>(self, rename: &F) -> Operand
+        where F: Fn(&str) -> String
+    {
+        match self {
+            Operand::Direct(expr) => Operand::Direct(expr.rename_symbols(rename)),
+            Operand::Immediate(expr) => Operand::Immediate(expr.rename_symbols(rename)),
+            Operand::Address(expr) => Operand::Address(expr.rename_symbols(rename)),
+            Operand::ComplementedBit(expr) => {
+                Operand::ComplementedBit(expr.rename_symbols(rename))
+            }
+            other => other,
+        }
+    }
 }
 
 impl Value {
-    pub fn into_bytes(self) -> Vec<u8> {
+    /// Replaces the name of every symbol in the value with `rename(name)`
+    pub fn rename_symbols<F>(self, rename: &F) -> Value
+        where F: Fn(&str) -> String
+    {
         match self {
-            Value::Byte(b) => vec![b],
-            Value::Word(w) => vec![(w % 256) as u8, (w / 256) as u8],
-            Value::String(s) => s.into_bytes(),
+            Value::Byte(expr) => Value::Byte(expr.rename_symbols(rename)),
+            Value::Word(expr) => Value::Word(expr.rename_symbols(rename)),
+            Value::String(s) => Value::String(s),
+        }
+    }
+
+    pub fn size(&self) -> u16 {
+        match *self {
+            Value::Byte(_) => 1,
+            Value::Word(_) => 2,
+            Value::String(ref s) => s.len() as u16,
         }
     }
 }
@@ -70,23 +125,112 @@ pub enum ParseError {
     ExpectedAt(lexer::Position),
     ExpectedHash(lexer::Position),
     ExpectedPlus(lexer::Position),
+    ExpectedSlash(lexer::Position),
+    ExpectedExpression(lexer::Position),
+    ExpectedRightParen(lexer::Position),
     InvalidLineBody(lexer::Position),
     InvalidMnemonic(String, lexer::Position),
-    InvalidOperand(lexer::Token),
+    /// A label or an EQU symbol named like a register or an SFR
+    PredefinedName(String, lexer::Position),
+    /// An operand that doesn't fit the mnemonic, along with the source it spans
+    InvalidOperand(Operator, lexer::Span),
     InvalidRegister(lexer::Token),
-    InvalidDirectAddr(u8),
-    InvalidNumber(String),
-    InvalidByte(i32),
-    InvalidWord(i32),
-    GeneralError,
+    InvalidNumber(String, lexer::Position),
+    /// A character constant that isn't one or two characters long
+    InvalidCharConstant(lexer::Position),
+    InvalidByte(i32, lexer::Position),
+    InvalidWord(i32, lexer::Position),
+}
+
+impl ParseError {
+    /// The place in the source where the error was found
+    pub fn position(&self) -> lexer::Position {
+        match *self {
+            ParseError::UnexpectedEof(ref tok) |
+            ParseError::InvalidRegister(ref tok) => tok.get_position(),
+            ParseError::InvalidOperand(_, span) => span.start(),
+            ParseError::ExpectedNewline(pos) |
+            ParseError::ExpectedIdentifier(pos) |
+            ParseError::ExpectedOperator(pos) |
+            ParseError::ExpectedKeyword(_, pos) |
+            ParseError::ExpectedNumber(pos) |
+            ParseError::ExpectedColon(pos) |
+            ParseError::ExpectedComma(pos) |
+            ParseError::ExpectedDot(pos) |
+            ParseError::ExpectedAt(pos) |
+            ParseError::ExpectedHash(pos) |
+            ParseError::ExpectedPlus(pos) |
+            ParseError::ExpectedSlash(pos) |
+            ParseError::ExpectedExpression(pos) |
+            ParseError::ExpectedRightParen(pos) |
+            ParseError::InvalidLineBody(pos) |
+            ParseError::InvalidMnemonic(_, pos) |
+            ParseError::PredefinedName(_, pos) |
+            ParseError::InvalidNumber(_, pos) |
+            ParseError::InvalidCharConstant(pos) |
+            ParseError::InvalidByte(_, pos) |
+            ParseError::InvalidWord(_, pos) => pos,
+        }
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::UnexpectedEof(_) => write!(f, "Unexpected end of the source"),
+            ParseError::ExpectedNewline(_) => write!(f, "Expected the end of the line"),
+            ParseError::ExpectedIdentifier(_) => write!(f, "Expected a name"),
+            ParseError::ExpectedOperator(_) => write!(f, "Expected a mnemonic"),
+            ParseError::ExpectedKeyword(kw, _) => write!(f, "Expected {}", kw),
+            ParseError::ExpectedNumber(_) => write!(f, "Expected a number"),
+            ParseError::ExpectedColon(_) => write!(f, "Expected ':'"),
+            ParseError::ExpectedComma(_) => write!(f, "Expected ','"),
+            ParseError::ExpectedDot(_) => write!(f, "Expected '.'"),
+            ParseError::ExpectedAt(_) => write!(f, "Expected '@'"),
+            ParseError::ExpectedHash(_) => write!(f, "Expected '#'"),
+            ParseError::ExpectedPlus(_) => write!(f, "Expected '+'"),
+            ParseError::ExpectedSlash(_) => write!(f, "Expected '/'"),
+            ParseError::ExpectedExpression(_) => write!(f, "Expected an expression"),
+            ParseError::ExpectedRightParen(_) => write!(f, "Expected ')'"),
+            ParseError::InvalidLineBody(_) => {
+                write!(f, "Expected an instruction, a data definition or a directive")
+            }
+            ParseError::InvalidMnemonic(ref s, _) => write!(f, "Unknown mnemonic {}", s),
+            ParseError::PredefinedName(ref s, _) => {
+                write!(f, "{} is the name of a register, an SFR or an SFR bit", s)
+            }
+            ParseError::InvalidOperand(op, _) => write!(f, "Invalid operand for {}", op),
+            ParseError::InvalidRegister(_) => write!(f, "Invalid register"),
+            ParseError::InvalidNumber(ref s, _) => write!(f, "Invalid number {}", s),
+            ParseError::InvalidCharConstant(_) => {
+                write!(f, "A character constant has to be one or two characters long")
+            }
+            ParseError::InvalidByte(value, _) => write!(f, "{} doesn't fit in a byte", value),
+            ParseError::InvalidWord(value, _) => write!(f, "{} doesn't fit in a word", value),
+        }
+    }
 }
 
 pub type Result<T> = ::std::result::Result<T, ParseError>;
 
+/// An error in parsing a line of the program
+#[derive(Clone, Debug, PartialEq)]
+pub struct SyntaxError {
+    pub span: SourceSpan,
+    pub error: ParseError,
+}
+
+impl Display for SyntaxError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.span, self.error)
+    }
+}
+
 #[derive(Clone, Debug)]
 pub struct ParserState<'a> {
     tokens: &'a Vec<lexer::Token>,
     position: usize,
+    file: Rc<str>,
 }
 
 pub struct ParseResult<'a, T> {
@@ -99,18 +243,22 @@ impl<'a> ParserState<'a> {
         ParserState {
             tokens: tokens,
             position: 0,
+            file: Rc::from(""),
         }
     }
 
-    pub fn parse(tokens: Vec<lexer::Token>) -> Result<Program> {
-        let parser = ParserState::new(&tokens);
-        let result = parser.parse_program()?;
-        Ok(result.result)
+    /// Parses the tokens of a program, naming `file` as their source in the spans of the nodes.
+    /// Returns the errors in all the lines that couldn't be parsed, if there are any.
+    pub fn parse(tokens: Vec<lexer::Token>,
+                 file: &str)
+                 -> ::std::result::Result<Program, Vec<SyntaxError>> {
+        let parser = ParserState { file: Rc::from(file), ..ParserState::new(&tokens) };
+        parser.parse_program()
     }
 
-    fn current_token(&self) -> Result<lexer::Token> {
+    fn current_token(&self) -> Result<&'a lexer::Token> {
         if self.position < self.tokens.len() {
-            Ok(self.tokens[self.position].clone())
+            Ok(&self.tokens[self.position])
         } else {
             Err(ParseError::UnexpectedEof(self.tokens[self.tokens.len() - 1].clone()))
         }
@@ -120,17 +268,64 @@ impl<'a> ParserState<'a> {
         ParserState {
             tokens: self.tokens,
             position: self.position + 1,
+            file: self.file,
+        }
+    }
+
+    /// The span of the source from the current token up to the last token before `end`,
+    /// leaving out the line break
+    fn span_to(&self, end: &ParserState<'a>) -> SourceSpan {
+        let start = self.tokens[self.position].get_position();
+        let length = self.tokens[self.position..end.position]
+            .iter()
+            .rev()
+            .find(|tok| !tok.is_newline())
+            .map_or(0, |tok| tok.get_span().end().column() - start.column());
+        SourceSpan {
+            file: self.file.clone(),
+            row: start.row(),
+            column: start.column(),
+            length,
         }
     }
 
+    /// The span of the token at which `error` was found, out of the tokens up to `end`, or
+    /// of the whole operand if it's an invalid one
+    fn error_span(&self, error: &ParseError, end: &ParserState<'a>) -> SourceSpan {
+        let pos = error.position();
+        let length = match *error {
+            ParseError::InvalidOperand(_, span) => span.end().column() - pos.column(),
+            _ => {
+                self.tokens[self.position..end.position]
+                    .iter()
+                    .find(|tok| tok.get_position() == pos && !tok.is_newline())
+                    .map_or(0, |tok| tok.get_span().end().column() - pos.column())
+            }
+        };
+        SourceSpan {
+            file: self.file.clone(),
+            row: pos.row(),
+            column: pos.column(),
+            length,
+        }
+    }
+
+    /// Runs `parse` and attaches the span of the parsed node to the result
+    fn located<T, F>(self, parse: F) -> Result<ParseResult<'a, Located<T>>>
+        where F: FnOnce(ParserState<'a>) -> Result<ParseResult<'a, T>>
+    {
+        let ParseResult { state, result } = parse(self.clone())?;
+        let span = self.span_to(&state);
+        Ok(ParseResult {
+               state,
+               result: Located { node: result, span },
+           })
+    }
+
     fn expect_keyword(self, kw: Keyword) -> Result<ParserState<'a>> {
         let cur_tok = self.current_token()?;
-        if let lexer::Token::Keyword(kw2, _) = cur_tok {
-            if kw2 == kw {
-                Ok(self.advanced())
-            } else {
-                Err(ParseError::ExpectedKeyword(kw, cur_tok.get_position()))
-            }
+        if cur_tok.keyword() == Some(kw) {
+            Ok(self.advanced())
         } else {
             Err(ParseError::ExpectedKeyword(kw, cur_tok.get_position()))
         }
@@ -139,15 +334,26 @@ impl<'a> ParserState<'a> {
     fn expect_identifier(self) -> Result<(ParserState<'a>, String)> {
         let cur_tok = self.current_token()?;
         if let lexer::Token::Identifier(s, _) = cur_tok {
-            Ok((self.advanced(), s))
+            Ok((self.advanced(), s.clone()))
         } else {
             Err(ParseError::ExpectedIdentifier(cur_tok.get_position()))
         }
     }
 
+    /// Expects the name of a new symbol, which can't name a register, an SFR or an SFR bit
+    fn expect_symbol_name(self) -> Result<(ParserState<'a>, String)> {
+        let cur_tok = self.current_token()?;
+        let (cur_state, name) = self.expect_identifier()?;
+        if name.parse::<Register>().is_ok() || cur_tok.direct_location().is_some() ||
+           cur_tok.bit_location().is_some() {
+            return Err(ParseError::PredefinedName(name, cur_tok.get_position()));
+        }
+        Ok((cur_state, name))
+    }
+
     fn expect_operator(self) -> Result<(ParserState<'a>, Operator)> {
         let cur_tok = self.current_token()?;
-        if let lexer::Token::Operator(oper, _) = cur_tok {
+        if let Some(oper) = cur_tok.operator() {
             Ok((self.advanced(), oper))
         } else {
             Err(ParseError::ExpectedOperator(cur_tok.get_position()))
@@ -205,6 +411,15 @@ impl<'a> ParserState<'a> {
         }
     }
 
+    fn expect_slash(self) -> Result<ParserState<'a>> {
+        let cur_tok = self.current_token()?;
+        if cur_tok.is_slash() {
+            Ok(self.advanced())
+        } else {
+            Err(ParseError::ExpectedSlash(cur_tok.get_position()))
+        }
+    }
+
     fn expect_plus(self) -> Result<ParserState<'a>> {
         let cur_tok = self.current_token()?;
         if cur_tok.is_plus() {
@@ -214,51 +429,92 @@ impl<'a> ParserState<'a> {
         }
     }
 
-    fn parse_program(self) -> Result<ParseResult<'a, Program>> {
+    /// Returns whether there are no more tokens in the current line
+    fn at_line_end(&self) -> bool {
+        self.current_token().map(|tok| tok.is_newline()).unwrap_or(true)
+    }
+
+    /// Returns whether the current token ends a value in a list
+    fn at_value_end(&self) -> bool {
+        self.at_line_end() || self.current_token().map(|tok| tok.is_comma()).unwrap_or(false)
+    }
+
+    /// Skips the rest of the current line, along with the line break
+    fn skip_line(self) -> ParserState<'a> {
+        let mut cur_state = self;
+        while let Ok(tok) = cur_state.current_token() {
+            cur_state = cur_state.advanced();
+            if tok.is_newline() {
+                break;
+            }
+        }
+        cur_state
+    }
+
+    fn parse_program(self) -> ::std::result::Result<Program, Vec<SyntaxError>> {
         let mut lines = Vec::new();
+        let mut errors = Vec::new();
 
         let mut cur_state = self;
 
         while cur_state.position < cur_state.tokens.len() {
-            let result = cur_state.parse_line()?;
-            cur_state = result.state;
-            lines.push(result.result);
+            match cur_state.clone().located(ParserState::parse_line) {
+                Ok(result) => {
+                    cur_state = result.state;
+                    lines.push(result.result);
+                }
+                Err(error) => {
+                    // carry on with the next line, so that all the errors get reported at once
+                    let next_state = cur_state.clone().skip_line();
+                    // lines with invalid tokens have already been reported by the tokenizer
+                    let line_tokens = &cur_state.tokens[cur_state.position..next_state.position];
+                    if !line_tokens.iter().any(lexer::Token::is_error) {
+                        errors.push(SyntaxError {
+                                        span: cur_state.error_span(&error, &next_state),
+                                        error,
+                                    });
+                    }
+                    cur_state = next_state;
+                }
+            }
         }
 
-        Ok(ParseResult {
-               state: cur_state,
-               result: Program { lines: lines },
-           })
+        if !errors.is_empty() {
+            return Err(errors);
+        }
+        Ok(Program { lines })
     }
 
     fn parse_line(self) -> Result<ParseResult<'a, Line>> {
-        let mut cur_state = self;
-
-        let result = cur_state.clone().parse_org_line();
-        if result.is_ok() {
-            return result;
+        // ORG and EQU lines are told apart by their keywords, so that their errors don't get
+        // lost in trying the other kinds of lines. A word followed by a colon is a label, even
+        // if it spells a keyword or a mnemonic.
+        let first_tok = self.current_token()?;
+        let next_tok = self.clone().advanced().current_token().ok();
+        let has_label = next_tok.as_ref().is_some_and(|tok| tok.is_colon());
+        if first_tok.keyword() == Some(Keyword::Org) && !has_label {
+            return self.parse_org_line();
         }
-
-        let result = cur_state.clone().parse_equ_def();
-        if result.is_ok() {
-            return result;
+        if next_tok.and_then(|tok| tok.keyword()) == Some(Keyword::Equ) {
+            return self.parse_equ_def();
         }
 
-        let result_label = cur_state.clone().parse_label();
+        let mut cur_state = self;
 
-        let label = if let Ok(parse_result_label) = result_label {
+        let label = if has_label {
+            let parse_result_label = cur_state.clone().located(ParserState::parse_label)?;
             cur_state = parse_result_label.state;
             Some(parse_result_label.result)
         } else {
             None
         };
 
-        let result_lbody = cur_state.clone().parse_line_body();
-        let lbody = if let Ok(parse_result_lbody) = result_lbody {
+        let lbody = if cur_state.at_line_end() {
+            None
+        } else {
+            let parse_result_lbody = cur_state.clone().located(ParserState::parse_line_body)?;
             cur_state = parse_result_lbody.state;
             Some(parse_result_lbody.result)
-        } else {
-            None
         };
 
         let newline_result = cur_state.expect_newline()?;
@@ -276,44 +532,34 @@ impl<'a> ParserState<'a> {
 
         let ParseResult {
             state: cur_state,
-            result: number,
-        } = cur_state.parse_number()?;
+            result: address,
+        } = cur_state.parse_expression()?;
 
         let cur_state = cur_state.expect_newline()?;
 
         Ok(ParseResult {
                state: cur_state,
-               result: Line::OrgLine { address: number as u16 },
+               result: Line::OrgLine { address },
            })
     }
 
     fn parse_equ_def(self) -> Result<ParseResult<'a, Line>> {
-        let (cur_state, id) = self.expect_identifier()?;
+        let (cur_state, id) = self.expect_symbol_name()?;
         let cur_state = cur_state.expect_keyword(Keyword::Equ)?;
         let ParseResult {
             state: cur_state,
-            result: number,
-        } = cur_state.parse_number()?;
+            result: value,
+        } = cur_state.parse_expression()?;
 
         let cur_state = cur_state.expect_newline()?;
         Ok(ParseResult {
                state: cur_state,
-               result: Line::EquDef {
-                   id: id,
-                   value: number,
-               },
+               result: Line::EquDef { id, value },
            })
     }
 
     fn parse_label(self) -> Result<ParseResult<'a, Label>> {
-        let cur_tok = self.current_token()?;
-        let label_txt = if cur_tok.is_identifier() {
-            cur_tok.get_string().unwrap()
-        } else {
-            return Err(ParseError::ExpectedIdentifier(cur_tok.get_position()));
-        };
-
-        let cur_state = self.advanced();
+        let (cur_state, label_txt) = self.expect_symbol_name()?;
         let cur_tok = cur_state.current_token()?;
 
         if !cur_tok.is_colon() {
@@ -327,18 +573,14 @@ impl<'a> ParserState<'a> {
     }
 
     fn parse_line_body(self) -> Result<ParseResult<'a, LineBody>> {
-        let result = self.clone().parse_code_line();
-        if result.is_ok() {
-            return result;
-        }
-
-        let result = self.clone().parse_value_def();
-        if result.is_ok() {
-            return result;
-        }
-
         let cur_tok = self.current_token()?;
-        Err(ParseError::InvalidLineBody(cur_tok.get_position()))
+        if cur_tok.operator().is_some() {
+            self.parse_code_line()
+        } else if cur_tok.is_identifier() {
+            self.parse_value_def()
+        } else {
+            Err(ParseError::InvalidLineBody(cur_tok.get_position()))
+        }
     }
 
     fn parse_code_line(self) -> Result<ParseResult<'a, LineBody>> {
@@ -346,9 +588,7 @@ impl<'a> ParserState<'a> {
 
         let mut operands = Vec::new();
 
-        let first_operand = cur_state.clone().parse_operand();
-
-        if let Err(_) = first_operand {
+        if cur_state.at_line_end() {
             return Ok(ParseResult {
                           state: cur_state,
                           result: LineBody::CodeLine {
@@ -361,14 +601,14 @@ impl<'a> ParserState<'a> {
         let ParseResult {
             state: mut cur_state,
             result: first_operand,
-        } = first_operand.unwrap();
+        } = cur_state.located(|state| state.parse_nth_operand(operator, 0))?;
         operands.push(first_operand);
 
         while let Ok(new_state) = cur_state.clone().expect_comma() {
             let ParseResult {
                 state: new_state2,
                 result: next_operand,
-            } = new_state.parse_operand()?;
+            } = new_state.located(|state| state.parse_nth_operand(operator, operands.len()))?;
             cur_state = new_state2;
             operands.push(next_operand);
         }
@@ -382,16 +622,46 @@ impl<'a> ParserState<'a> {
            })
     }
 
-    fn parse_base(s: &str, base: u8) -> i32 {
-        let negative = s.starts_with("-");
-        let unsigned_s = if negative { &s[1..] } else { &s[..] };
+    fn parse_base(digits: &str, base: u32) -> Option<i32> {
+        if digits.is_empty() {
+            return None;
+        }
         let mut result: i32 = 0;
-        for c in unsigned_s.chars() {
-            let digit = c.to_digit(base as u32).unwrap();
-            result *= base as i32;
-            result += digit as i32;
+        for c in digits.chars() {
+            let digit = c.to_digit(base)?;
+            result = result.checked_mul(base as i32)?.checked_add(digit as i32)?;
         }
-        if negative { -result } else { result }
+        Some(result)
+    }
+
+    fn parse_literal(literal: &str) -> Option<i32> {
+        // Intel suffixes, along with the C and Motorola-style prefixes
+        let is_binary = |s: &str| s.chars().all(|c| c == '0' || c == '1');
+        if let Some(digits) = literal.strip_prefix("0x") {
+            return Self::parse_base(digits, 16);
+        }
+        if let Some(digits) = literal.strip_prefix("0b") {
+            if !digits.is_empty() && is_binary(digits) {
+                return Self::parse_base(digits, 2);
+            }
+        }
+        if let Some(digits) = literal.strip_prefix('$') {
+            return Self::parse_base(digits, 16);
+        }
+        if let Some(digits) = literal.strip_prefix('%') {
+            return Self::parse_base(digits, 2);
+        }
+
+        let (digits, base) = match literal.chars().last() {
+            Some('h') if literal.starts_with(|c: char| c.is_ascii_digit()) => {
+                (&literal[..literal.len() - 1], 16)
+            }
+            Some('b') => (&literal[..literal.len() - 1], 2),
+            Some('o') | Some('q') => (&literal[..literal.len() - 1], 8),
+            Some('d') => (&literal[..literal.len() - 1], 10),
+            _ => (literal, 10),
+        };
+        Self::parse_base(digits, base)
     }
 
     fn parse_number(self) -> Result<ParseResult<'a, i32>> {
@@ -401,70 +671,88 @@ impl<'a> ParserState<'a> {
         }
 
         let num_string = cur_tok.get_string().unwrap().to_lowercase();
-        let dec_re = Regex::new(r"^(-?[0-9]+)$").unwrap();
-        let bin_re = Regex::new(r"^(-?[01]+)b$").unwrap();
-        let hex_re = Regex::new(r"^(-?[0-9][0-9a-f]*)h$").unwrap();
-        let oct_re = Regex::new(r"^(-?[0-7]+)o$").unwrap();
-
-        if let Some(caps) = bin_re.captures(&num_string) {
-            return Ok(ParseResult {
-                          state: self.advanced(),
-                          result: ParserState::parse_base(caps.at(1).unwrap(), 2),
-                      });
-        } else if let Some(caps) = oct_re.captures(&num_string) {
-            return Ok(ParseResult {
-                          state: self.advanced(),
-                          result: ParserState::parse_base(caps.at(1).unwrap(), 8),
-                      });
-        } else if let Some(caps) = dec_re.captures(&num_string) {
-            return Ok(ParseResult {
-                          state: self.advanced(),
-                          result: ParserState::parse_base(caps.at(1).unwrap(), 10),
-                      });
-        } else if let Some(caps) = hex_re.captures(&num_string) {
-            return Ok(ParseResult {
-                          state: self.advanced(),
-                          result: ParserState::parse_base(caps.at(1).unwrap(), 16),
-                      });
+        match ParserState::parse_literal(&num_string.replace("_", "")) {
+            Some(value) => {
+                Ok(ParseResult {
+                       state: self.advanced(),
+                       result: value,
+                   })
+            }
+            None => Err(ParseError::InvalidNumber(num_string, cur_tok.get_position())),
         }
-
-        Err(ParseError::InvalidNumber(num_string))
     }
 
     fn parse_operand(self) -> Result<ParseResult<'a, Operand>> {
-        let res_indirect_sum = self.clone().parse_indirect_sum();
-        if res_indirect_sum.is_ok() {
-            return res_indirect_sum;
+        let cur_tok = self.current_token()?;
+        match *cur_tok {
+            lexer::Token::At(_) => self.parse_indirect(),
+            lexer::Token::Hash(_) => self.parse_immediate(),
+            lexer::Token::Slash(_) => self.parse_complemented_bit(),
+            lexer::Token::Identifier(ref s, _) if s.parse::<Register>().is_ok() => {
+                let ParseResult { state, result } = self.parse_register()?;
+                Ok(ParseResult {
+                       state,
+                       result: Operand::Register(result),
+                   })
+            }
+            _ => self.parse_direct(),
         }
+    }
 
-        let res_indirect = self.clone().parse_indirect();
-        if res_indirect.is_ok() {
-            return res_indirect;
+    fn parse_nth_operand(self,
+                         operator: Operator,
+                         index: usize)
+                         -> Result<ParseResult<'a, Operand>> {
+        let result = if operator.target_operand() == Some(index) {
+            self.clone().parse_target()
+        } else {
+            self.clone().parse_operand()
+        };
+        match *self.current_token()? {
+            lexer::Token::At(span) => result.map_err(|e| self.invalid_operand(operator, span, &e)),
+            _ => result,
         }
+    }
 
-        let res_immediate = self.clone().parse_immediate();
-        if res_immediate.is_ok() {
-            return res_immediate;
-        }
+    /// `@` followed by anything but a register is the wrong operand for the mnemonic rather than
+    /// a typo, so the whole operand is reported, from `start` up to where it stops making sense
+    fn invalid_operand(&self,
+                       operator: Operator,
+                       start: lexer::Span,
+                       error: &ParseError)
+                       -> ParseError {
+        let end = self.tokens[self.position..]
+            .iter()
+            .find(|tok| tok.get_position() == error.position() && !tok.is_newline())
+            .map_or(start, |tok| tok.get_span());
+        ParseError::InvalidOperand(operator, start.to(&end))
+    }
 
-        let res_register = self.clone().parse_register();
-        if let Ok(result) = res_register {
-            return Ok(ParseResult {
-                          state: result.state,
-                          result: Operand::Register(result.result),
-                      });
+    fn parse_target(self) -> Result<ParseResult<'a, Operand>> {
+        match *self.current_token()? {
+            lexer::Token::At(_) | lexer::Token::Hash(_) => self.parse_operand(),
+            _ => self.parse_address(),
         }
+    }
 
-        let res_direct = self.clone().parse_direct();
-        if res_direct.is_ok() {
-            return res_direct;
+    fn parse_address(self) -> Result<ParseResult<'a, Operand>> {
+        let pos = self.current_token()?.get_position();
+        let ParseResult {
+            state: cur_state,
+            result: address,
+        } = self.parse_expression()?;
+
+        if let Some(value) = address.constant() {
+            Self::to_word(value, pos)?;
         }
 
-        let cur_tok = self.current_token()?;
-        Err(ParseError::InvalidOperand(cur_tok))
+        Ok(ParseResult {
+               state: cur_state,
+               result: Operand::Address(address),
+           })
     }
 
-    fn parse_indirect_sum(self) -> Result<ParseResult<'a, Operand>> {
+    fn parse_indirect(self) -> Result<ParseResult<'a, Operand>> {
         let cur_state = self.expect_at()?;
 
         let ParseResult {
@@ -472,7 +760,15 @@ impl<'a> ParserState<'a> {
             result: register1,
         } = cur_state.parse_register()?;
 
-        let cur_state = cur_state.expect_plus()?;
+        let cur_state = match cur_state.clone().expect_plus() {
+            Ok(state) => state,
+            Err(_) => {
+                return Ok(ParseResult {
+                              state: cur_state,
+                              result: Operand::IndirectReg(register1),
+                          })
+            }
+        };
 
         let ParseResult {
             state: cur_state,
@@ -485,44 +781,37 @@ impl<'a> ParserState<'a> {
            })
     }
 
-    fn parse_indirect(self) -> Result<ParseResult<'a, Operand>> {
-        let cur_state = self.expect_at()?;
+    fn parse_complemented_bit(self) -> Result<ParseResult<'a, Operand>> {
+        let cur_state = self.expect_slash()?;
 
+        let pos = cur_state.current_token()?.get_position();
         let ParseResult {
             state: cur_state,
-            result: register,
-        } = cur_state.parse_register()?;
+            result: bit,
+        } = cur_state.parse_expression()?;
+
+        if let Some(value) = bit.constant() {
+            Self::to_byte(value, pos)?;
+        }
 
         Ok(ParseResult {
                state: cur_state,
-               result: Operand::IndirectReg(register),
+               result: Operand::ComplementedBit(bit),
            })
     }
 
     fn parse_immediate(self) -> Result<ParseResult<'a, Operand>> {
         let cur_state = self.expect_hash()?;
 
-        let cur_tok = cur_state.current_token()?;
-
-        if cur_tok.is_identifier() {
-            return Ok(ParseResult {
-                          state: cur_state.advanced(),
-                          result: Operand::ImmediateId(cur_tok.get_string().unwrap()),
-                      });
-        }
-
-        if cur_tok.is_number() {
-            let ParseResult {
-                state: cur_state,
-                result: number,
-            } = cur_state.parse_number()?;
-            return Ok(ParseResult {
-                          state: cur_state,
-                          result: Operand::Immediate(number),
-                      });
-        }
+        let ParseResult {
+            state: cur_state,
+            result: expr,
+        } = cur_state.parse_expression()?;
 
-        Err(ParseError::GeneralError)
+        Ok(ParseResult {
+               state: cur_state,
+               result: Operand::Immediate(expr),
+           })
     }
 
     fn parse_register(self) -> Result<ParseResult<'a, Register>> {
@@ -534,7 +823,7 @@ impl<'a> ParserState<'a> {
                        result: reg_result,
                    })
             } else {
-                Err(ParseError::InvalidRegister(cur_tok))
+                Err(ParseError::InvalidRegister(cur_tok.clone()))
             }
         } else {
             Err(ParseError::ExpectedIdentifier(cur_tok.get_position()))
@@ -542,45 +831,207 @@ impl<'a> ParserState<'a> {
     }
 
     fn parse_direct(self) -> Result<ParseResult<'a, Operand>> {
-        let cur_state = self;
-        let cur_tok = cur_state.current_token()?;
-        let (mut cur_state, mut address) = if let lexer::Token::DirectLocation(dir, _) = cur_tok {
-            (cur_state.advanced(), dir.get_addr())
-        } else if cur_tok.is_number() {
-            let ParseResult {
-                state: cur_state,
-                result: number,
-            } = cur_state.parse_number()?;
-            (cur_state, Self::to_byte(number)?)
-        } else {
-            return Err(ParseError::ExpectedIdentifier(cur_tok.get_position()));
-        };
+        let pos = self.current_token()?.get_position();
+        let ParseResult {
+            state: cur_state,
+            result: address,
+        } = self.parse_expression()?;
 
-        if let Ok(ParseResult {
-                      state: new_state,
-                      result: bit_num,
-                  }) = cur_state.clone().parse_bit() {
-            address = Self::direct_bit(address, bit_num)?;
-            cur_state = new_state;
+        if let Some(value) = address.constant() {
+            Self::to_byte(value, pos)?;
         }
+
         Ok(ParseResult {
                state: cur_state,
                result: Operand::Direct(address),
            })
     }
 
-    fn parse_bit(self) -> Result<ParseResult<'a, u8>> {
-        let cur_state = self.expect_dot()?;
+    fn parse_expression(self) -> Result<ParseResult<'a, Expr>> {
+        self.parse_binary(Self::parse_and_expr, |tok| match (tok, tok.keyword()) {
+            (&lexer::Token::Pipe(_), _) |
+            (_, Some(Keyword::Or)) => Some(BinaryOperator::Or),
+            (&lexer::Token::Caret(_), _) |
+            (_, Some(Keyword::Xor)) => Some(BinaryOperator::Xor),
+            _ => None,
+        })
+    }
+
+    fn parse_and_expr(self) -> Result<ParseResult<'a, Expr>> {
+        self.parse_binary(Self::parse_shift_expr, |tok| match (tok, tok.keyword()) {
+            (&lexer::Token::Ampersand(_), _) |
+            (_, Some(Keyword::And)) => Some(BinaryOperator::And),
+            _ => None,
+        })
+    }
+
+    fn parse_shift_expr(self) -> Result<ParseResult<'a, Expr>> {
+        self.parse_binary(Self::parse_additive_expr, |tok| match (tok, tok.keyword()) {
+            (&lexer::Token::ShiftLeft(_), _) |
+            (_, Some(Keyword::Shl)) => Some(BinaryOperator::Shl),
+            (&lexer::Token::ShiftRight(_), _) |
+            (_, Some(Keyword::Shr)) => Some(BinaryOperator::Shr),
+            _ => None,
+        })
+    }
+
+    fn parse_additive_expr(self) -> Result<ParseResult<'a, Expr>> {
+        self.parse_binary(Self::parse_multiplicative_expr, |tok| match *tok {
+            lexer::Token::Plus(_) => Some(BinaryOperator::Add),
+            lexer::Token::Minus(_) => Some(BinaryOperator::Sub),
+            _ => None,
+        })
+    }
+
+    fn parse_multiplicative_expr(self) -> Result<ParseResult<'a, Expr>> {
+        self.parse_binary(Self::parse_unary_expr, |tok| match (tok, tok.keyword()) {
+            (&lexer::Token::Asterisk(_), _) => Some(BinaryOperator::Mul),
+            (&lexer::Token::Slash(_), _) => Some(BinaryOperator::Div),
+            (&lexer::Token::Percent(_), _) |
+            (_, Some(Keyword::Mod)) => Some(BinaryOperator::Mod),
+            _ => None,
+        })
+    }
+
+    /// Parses a left-associative chain of `next`-level expressions joined by
+    /// the binary operators recognized by `operator`
+    fn parse_binary<N, O>(self, next: N, operator: O) -> Result<ParseResult<'a, Expr>>
+        where N: Fn(ParserState<'a>) -> Result<ParseResult<'a, Expr>>,
+              O: Fn(&lexer::Token) -> Option<BinaryOperator>
+    {
+        let ParseResult {
+            state: mut cur_state,
+            result: mut expr,
+        } = next(self)?;
+
+        while let Some(op) = cur_state.current_token().ok().and_then(&operator) {
+            let ParseResult {
+                state: new_state,
+                result: right,
+            } = next(cur_state.advanced())?;
+            expr = Expr::binary(op, expr, right);
+            cur_state = new_state;
+        }
+
+        Ok(ParseResult {
+               state: cur_state,
+               result: expr,
+           })
+    }
+
+    fn parse_unary_expr(self) -> Result<ParseResult<'a, Expr>> {
+        let cur_tok = self.current_token()?;
+        let op = match (&cur_tok, cur_tok.keyword()) {
+            (&lexer::Token::Plus(_), _) => return self.advanced().parse_unary_expr(),
+            (&lexer::Token::Minus(_), _) => UnaryOperator::Neg,
+            (&lexer::Token::Tilde(_), _) |
+            (_, Some(Keyword::Not)) => UnaryOperator::Not,
+            _ => return self.parse_bit_expr(),
+        };
+
         let ParseResult {
             state: cur_state,
-            result: number,
-        } = cur_state.parse_number()?;
+            result: expr,
+        } = self.advanced().parse_unary_expr()?;
+
         Ok(ParseResult {
                state: cur_state,
-               result: Self::to_byte(number)?,
+               result: Expr::unary(op, expr),
            })
     }
 
+    fn parse_bit_expr(self) -> Result<ParseResult<'a, Expr>> {
+        let ParseResult {
+            state: cur_state,
+            result: byte,
+        } = self.parse_primary_expr()?;
+
+        let cur_state = match cur_state.clone().expect_dot() {
+            Ok(new_state) => new_state,
+            Err(_) => {
+                return Ok(ParseResult {
+                              state: cur_state,
+                              result: byte,
+                          })
+            }
+        };
+
+        let ParseResult {
+            state: cur_state,
+            result: bit,
+        } = cur_state.parse_primary_expr()?;
+
+        Ok(ParseResult {
+               state: cur_state,
+               result: Expr::bit(byte, bit),
+           })
+    }
+
+    fn parse_primary_expr(self) -> Result<ParseResult<'a, Expr>> {
+        let cur_tok = self.current_token()?;
+        match cur_tok {
+            lexer::Token::Number(_, _) => {
+                let ParseResult {
+                    state: cur_state,
+                    result: number,
+                } = self.parse_number()?;
+                Ok(ParseResult {
+                       state: cur_state,
+                       result: Expr::Number(number),
+                   })
+            }
+            lexer::Token::Identifier(ref s, _) => {
+                // SFR names stand for their addresses, and SFR bit names for the `byte.bit` they
+                // name, so that they can't be mistaken for bytes
+                let result = match (cur_tok.direct_location(), cur_tok.bit_location()) {
+                    (Some(dir), _) => Expr::Number(dir.get_addr() as i32),
+                    (_, Some(bit)) => {
+                        let (byte, bit) = bit.location();
+                        Expr::bit(Expr::Number(byte.get_addr() as i32), Expr::Number(bit as i32))
+                    }
+                    _ => Expr::Symbol(s.clone()),
+                };
+                Ok(ParseResult {
+                       state: self.advanced(),
+                       result,
+                   })
+            }
+            lexer::Token::Dollar(_) => {
+                Ok(ParseResult {
+                       state: self.advanced(),
+                       result: Expr::Location,
+                   })
+            }
+            lexer::Token::String(s, pos) => {
+                // a character constant; two characters make a word, the first one in the high byte
+                if s.is_empty() || s.len() > 2 {
+                    return Err(ParseError::InvalidCharConstant(pos.start()));
+                }
+                let value = s.iter().fold(0, |acc, &c| (acc << 8) | c as i32);
+                Ok(ParseResult {
+                       state: self.advanced(),
+                       result: Expr::Number(value),
+                   })
+            }
+            lexer::Token::LeftParen(_) => {
+                let ParseResult {
+                    state: cur_state,
+                    result: expr,
+                } = self.advanced().parse_expression()?;
+                let cur_tok = cur_state.current_token()?;
+                if let lexer::Token::RightParen(_) = cur_tok {
+                    Ok(ParseResult {
+                           state: cur_state.advanced(),
+                           result: expr,
+                       })
+                } else {
+                    Err(ParseError::ExpectedRightParen(cur_tok.get_position()))
+                }
+            }
+            _ => Err(ParseError::ExpectedExpression(cur_tok.get_position())),
+        }
+    }
+
     fn parse_value_def(self) -> Result<ParseResult<'a, LineBody>> {
         let ParseResult {
             state: cur_state,
@@ -619,13 +1070,13 @@ impl<'a> ParserState<'a> {
            })
     }
 
-    fn parse_bytes(self) -> Result<ParseResult<'a, Vec<Value>>> {
+    fn parse_bytes(self) -> Result<ParseResult<'a, Vec<Located<Value>>>> {
         let mut values = Vec::new();
 
         let ParseResult {
             state: mut cur_state,
             result: first_value,
-        } = self.parse_byte_value()?;
+        } = self.located(ParserState::parse_byte_value)?;
 
         values.push(first_value);
 
@@ -633,7 +1084,7 @@ impl<'a> ParserState<'a> {
             let ParseResult {
                 state: new_state2,
                 result: next_value,
-            } = new_state.parse_byte_value()?;
+            } = new_state.located(ParserState::parse_byte_value)?;
             cur_state = new_state2;
             values.push(next_value);
         }
@@ -644,23 +1095,23 @@ impl<'a> ParserState<'a> {
            })
     }
 
-    fn parse_words(self) -> Result<ParseResult<'a, Vec<Value>>> {
+    fn parse_words(self) -> Result<ParseResult<'a, Vec<Located<Value>>>> {
         let mut values = Vec::new();
 
         let ParseResult {
             state: mut cur_state,
-            result: first_number,
-        } = self.parse_number()?;
+            result: first_value,
+        } = self.located(ParserState::parse_word_value)?;
 
-        values.push(Value::Word(Self::to_word(first_number)?));
+        values.push(first_value);
 
         while let Ok(new_state) = cur_state.clone().expect_comma() {
             let ParseResult {
                 state: new_state2,
                 result: next_value,
-            } = new_state.parse_number()?;
+            } = new_state.located(ParserState::parse_word_value)?;
             cur_state = new_state2;
-            values.push(Value::Word(Self::to_word(next_value)?));
+            values.push(next_value);
         }
 
         Ok(ParseResult {
@@ -672,47 +1123,64 @@ impl<'a> ParserState<'a> {
     fn parse_byte_value(self) -> Result<ParseResult<'a, Value>> {
         let cur_tok = self.current_token()?;
         if let lexer::Token::String(s, _) = cur_tok {
-            return Ok(ParseResult {
-                          state: self.advanced(),
-                          result: Value::String(s),
-                      });
+            // a string followed by an operator is a character constant in an expression
+            let next_state = self.clone().advanced();
+            if next_state.at_value_end() {
+                return Ok(ParseResult {
+                              state: next_state,
+                              result: Value::String(s.clone()),
+                          });
+            }
         }
 
+        let pos = self.current_token()?.get_position();
         let ParseResult {
             state: cur_state,
             result: byte,
-        } = self.parse_number()?;
+        } = self.parse_expression()?;
+
+        if let Some(value) = byte.constant() {
+            Self::to_byte(value, pos)?;
+        }
 
         Ok(ParseResult {
                state: cur_state,
-               result: Value::Byte(Self::to_byte(byte)?),
+               result: Value::Byte(byte),
            })
     }
 
-    fn to_byte(byte: i32) -> Result<u8> {
+    fn parse_word_value(self) -> Result<ParseResult<'a, Value>> {
+        let pos = self.current_token()?.get_position();
+        let ParseResult {
+            state: cur_state,
+            result: word,
+        } = self.parse_expression()?;
+
+        if let Some(value) = word.constant() {
+            Self::to_word(value, pos)?;
+        }
+
+        Ok(ParseResult {
+               state: cur_state,
+               result: Value::Word(word),
+           })
+    }
+
+    /// Checks that the value of the expression starting at `pos` fits in a byte
+    fn to_byte(byte: i32, pos: lexer::Position) -> Result<u8> {
         if byte >= 0 && byte <= 255 {
             Ok(byte as u8)
         } else {
-            Err(ParseError::InvalidByte(byte))
+            Err(ParseError::InvalidByte(byte, pos))
         }
     }
 
-    fn to_word(word: i32) -> Result<u16> {
+    /// Checks that the value of the expression starting at `pos` fits in a word
+    fn to_word(word: i32, pos: lexer::Position) -> Result<u16> {
         if word >= 0 && word <= 65535 {
             Ok(word as u16)
         } else {
-            Err(ParseError::InvalidWord(word))
-        }
-    }
-
-    fn direct_bit(addr: u8, bit_num: u8) -> Result<u8> {
-        assert!(bit_num < 8);
-        if addr >= 0x20 && addr < 0x30 {
-            Ok((addr - 0x20) * 8 + bit_num)
-        } else if addr >= 0x80 {
-            Ok(addr + bit_num)
-        } else {
-            Err(ParseError::InvalidDirectAddr(addr))
+            Err(ParseError::InvalidWord(word, pos))
         }
     }
 }
@@ -720,6 +1188,7 @@ impl<'a> ParserState<'a> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use super::super::expr::*;
     use super::super::keywords::*;
     use super::super::lexer::{Token, Tokenizer};
 
@@ -727,6 +1196,19 @@ mod test {
         Tokenizer::tokenize(s).unwrap()
     }
 
+    /// A node parsed from the first line of a test snippet
+    fn at<T>(node: T, column: usize, length: usize) -> Located<T> {
+        Located {
+            node,
+            span: SourceSpan {
+                file: Rc::from(""),
+                row: 1,
+                column,
+                length,
+            },
+        }
+    }
+
     // Operands tests
 
     // Registers
@@ -740,6 +1222,21 @@ mod test {
         assert_eq!(result.unwrap().result, Register::R(1));
     }
 
+    #[test]
+    fn test_parse_register_case() {
+        for &(text, ref register) in [("r3", Register::R(3)),
+                                  ("R3", Register::R(3)),
+                                  ("Dptr", Register::DPTR),
+                                  ("a", Register::A)]
+                    .iter() {
+            let tokens = tokens(text);
+            let state = ParserState::new(&tokens);
+            let result = state.parse_register();
+            assert!(result.is_ok(), "{}", text);
+            assert_eq!(&result.unwrap().result, register);
+        }
+    }
+
     #[test]
     fn test_parse_register_fail() {
         let tokens = tokens("R8");
@@ -794,6 +1291,37 @@ mod test {
         assert_eq!(result.unwrap().result, 205);
     }
 
+    #[test]
+    fn test_number_alternative_formats() {
+        let cases = [("0x1F", 0x1F),
+                     ("0X1f", 0x1F),
+                     ("$1F", 0x1F),
+                     ("%1010", 10),
+                     ("0b1010", 10),
+                     ("17q", 15),
+                     ("17Q", 15),
+                     ("99d", 99),
+                     ("1_000", 1000),
+                     ("0FF_FFh", 0xFFFF),
+                     ("0b", 0)];
+        for &(text, value) in cases.iter() {
+            let tokens = tokens(text);
+            let state = ParserState::new(&tokens);
+            let result = state.parse_number();
+            assert!(result.is_ok(), "{}: {:?}", text, result.err());
+            assert_eq!(result.unwrap().result, value, "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_number_invalid_formats() {
+        for text in ["0x", "$", "%2", "0b12", "12x", "19q", "100000000000h"].iter() {
+            let tokens = tokens(text);
+            let state = ParserState::new(&tokens);
+            assert!(state.parse_number().is_err(), "{}", text);
+        }
+    }
+
     #[test]
     fn test_number_invalid() {
         let tokens = tokens("0cdo");
@@ -808,7 +1336,7 @@ mod test {
         let state = ParserState::new(&tokens);
         let result = state.parse_immediate();
         assert!(result.is_ok());
-        assert_eq!(result.unwrap().result, Operand::Immediate(171));
+        assert_eq!(result.unwrap().result, Operand::Immediate(Expr::Number(171)));
     }
 
     #[test]
@@ -818,7 +1346,120 @@ mod test {
         let result = state.parse_immediate();
         assert!(result.is_ok());
         assert_eq!(result.unwrap().result,
-                   Operand::ImmediateId("label".to_string()));
+                   Operand::Immediate(Expr::Symbol("label".to_string())));
+    }
+
+    #[test]
+    fn test_immediate_negative() {
+        let tokens = tokens("#-1");
+        let state = ParserState::new(&tokens);
+        let result = state.parse_immediate();
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap().result,
+                   Operand::Immediate(Expr::unary(UnaryOperator::Neg, Expr::Number(1))));
+    }
+
+    #[test]
+    fn test_immediate_expression() {
+        let tokens = tokens("#(BUFSIZE-1)");
+        let state = ParserState::new(&tokens);
+        let result = state.parse_immediate();
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap().result,
+                   Operand::Immediate(Expr::binary(BinaryOperator::Sub,
+                                                   Expr::Symbol("BUFSIZE".to_owned()),
+                                                   Expr::Number(1))));
+    }
+
+    // Expressions
+
+    #[test]
+    fn test_expression_precedence() {
+        let tokens = tokens("TABLE+2*ENTRY");
+        let state = ParserState::new(&tokens);
+        let result = state.parse_expression();
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap().result,
+                   Expr::binary(BinaryOperator::Add,
+                                Expr::Symbol("TABLE".to_owned()),
+                                Expr::binary(BinaryOperator::Mul,
+                                             Expr::Number(2),
+                                             Expr::Symbol("ENTRY".to_owned()))));
+    }
+
+    #[test]
+    fn test_expression_constant() {
+        let cases = [("(1 + 2) * 3", 9),
+                     ("1 + 2 * 3", 7),
+                     ("10 - 4 - 3", 3),
+                     ("17 MOD 5 + 100 / 10", 12),
+                     ("1 SHL 4 OR 1", 17),
+                     ("0F0h AND 3Ch XOR 0FFh", 0xCF),
+                     ("1 + 1 SHL 2", 8),
+                     ("NOT 0 AND 0FFh", 0xFF),
+                     ("--5", 5),
+                     ("-(2 + 3) * 2", -10),
+                     ("1 << 4 | 1", 17),
+                     ("0F0h & 3Ch ^ 0FFh", 0xCF),
+                     ("(100h >> 4) % 7", 2),
+                     ("~0 & 0FFh", 0xFF),
+                     ("'0' + 5", 0x35),
+                     ("\"AB\"", 0x4142),
+                     ("'''' - 1", 0x26)];
+        for &(text, value) in cases.iter() {
+            let tokens = tokens(text);
+            let state = ParserState::new(&tokens);
+            let result = state.parse_expression();
+            assert!(result.is_ok(), "{}: {:?}", text, result.err());
+            assert_eq!(result.unwrap().result.constant(), Some(value), "{}", text);
+        }
+    }
+
+    #[test]
+    fn test_define_bytes_quoted() {
+        let tokens = tokens("db 'It''s', 'A' + 1, \"\\x00\", ''");
+        let state = ParserState::new(&tokens);
+        let result = state.parse_value_def();
+        assert!(result.is_ok());
+        let values: Vec<_> = match result.unwrap().result {
+            LineBody::ValueDefinition { values } => values.into_iter().map(|v| v.node).collect(),
+            other => panic!("Expected a value definition, got {:?}", other),
+        };
+        assert_eq!(values,
+                   vec![Value::String(b"It's".to_vec()),
+                        Value::Byte(Expr::binary(BinaryOperator::Add,
+                                                 Expr::Number(0x41),
+                                                 Expr::Number(1))),
+                        Value::String(vec![0]),
+                        Value::String(vec![])]);
+    }
+
+    #[test]
+    fn test_immediate_char_constant() {
+        let tokens = tokens("#'0'");
+        let state = ParserState::new(&tokens);
+        let result = state.parse_immediate();
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap().result, Operand::Immediate(Expr::Number(0x30)));
+    }
+
+    #[test]
+    fn test_immediate_char_constant_too_long() {
+        let tokens = tokens("#'abc'");
+        let state = ParserState::new(&tokens);
+        match state.parse_immediate() {
+            Err(ParseError::InvalidCharConstant(pos)) => assert_eq!(pos.column(), 2),
+            Err(other) => panic!("Expected an invalid character constant error, got {:?}", other),
+            Ok(_) => panic!("Parsed a three-character constant"),
+        }
+    }
+
+    #[test]
+    fn test_expression_unbalanced() {
+        let tokens = tokens("(1 + 2");
+        let state = ParserState::new(&tokens);
+        let result = state.parse_expression();
+        assert!(result.is_err());
     }
 
     #[test]
@@ -829,10 +1470,10 @@ mod test {
         assert!(result.is_ok());
         assert_eq!(result.unwrap().result,
                    LineBody::ValueDefinition {
-                       values: vec![Value::String("foo bar quux".to_owned()),
-                                    Value::Byte(13),
-                                    Value::Byte(10),
-                                    Value::Byte(0)],
+                       values: vec![at(Value::String(b"foo bar quux".to_vec()), 4, 14),
+                                    at(Value::Byte(Expr::Number(13)), 20, 2),
+                                    at(Value::Byte(Expr::Number(10)), 24, 2),
+                                    at(Value::Byte(Expr::Number(0)), 28, 1)],
                    });
     }
 
@@ -844,7 +1485,9 @@ mod test {
         assert!(result.is_ok());
         assert_eq!(result.unwrap().result,
                    LineBody::ValueDefinition {
-                       values: vec![Value::Word(278), Value::Word(10765), Value::Word(13)],
+                       values: vec![at(Value::Word(Expr::Number(278)), 4, 3),
+                                    at(Value::Word(Expr::Number(10765)), 9, 5),
+                                    at(Value::Word(Expr::Number(13)), 16, 2)],
                    });
     }
 
@@ -870,7 +1513,7 @@ mod test {
         let state = ParserState::new(&tokens);
         let result = state.parse_operand();
         assert!(result.is_ok());
-        assert_eq!(result.unwrap().result, Operand::Direct(0x81));
+        assert_eq!(result.unwrap().result, Operand::Direct(Expr::Number(0x81)));
     }
 
     #[test]
@@ -879,7 +1522,7 @@ mod test {
         let state = ParserState::new(&tokens);
         let result = state.parse_operand();
         assert!(result.is_ok());
-        assert_eq!(result.unwrap().result, Operand::Direct(0xF0));
+        assert_eq!(result.unwrap().result, Operand::Direct(Expr::Number(0xF0)));
     }
 
     #[test]
@@ -888,7 +1531,165 @@ mod test {
         let state = ParserState::new(&tokens);
         let result = state.parse_operand();
         assert!(result.is_ok(), "{:?}", result.err().unwrap());
-        assert_eq!(result.unwrap().result, Operand::Direct(0xFE));
+        if let Operand::Direct(expr) = result.unwrap().result {
+            assert_eq!(expr.constant(), Some(0xFE));
+        } else {
+            panic!("Expected a direct operand");
+        }
+    }
+
+    #[test]
+    fn test_parse_direct_symbol() {
+        let tokens = tokens("flags.3, r8");
+        let state = ParserState::new(&tokens);
+        let ParseResult { state, result } = state.parse_operand().unwrap();
+        assert_eq!(result,
+                   Operand::Direct(Expr::bit(Expr::Symbol("flags".to_owned()), Expr::Number(3))));
+        let result = state.expect_comma().unwrap().parse_operand().unwrap().result;
+        assert_eq!(result, Operand::Direct(Expr::Symbol("r8".to_owned())));
+    }
+
+    #[test]
+    fn test_parse_sfr_bit() {
+        let tokens = tokens("TR0");
+        let state = ParserState::new(&tokens);
+        let result = state.parse_operand();
+        assert_eq!(result.unwrap().result,
+                   Operand::Direct(Expr::bit(Expr::Number(0x88), Expr::Number(4))));
+    }
+
+    #[test]
+    fn test_parse_complemented_bit() {
+        let tokens = tokens("/P1.2");
+        let state = ParserState::new(&tokens);
+        let result = state.parse_operand();
+        assert_eq!(result.unwrap().result,
+                   Operand::ComplementedBit(Expr::bit(Expr::Number(0x90), Expr::Number(2))));
+    }
+
+    #[test]
+    fn test_parse_direct_expression() {
+        let tokens = tokens("20h+2*3");
+        let state = ParserState::new(&tokens);
+        let result = state.parse_operand();
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap().result,
+                   Operand::Direct(Expr::binary(BinaryOperator::Add,
+                                                Expr::Number(0x20),
+                                                Expr::binary(BinaryOperator::Mul,
+                                                             Expr::Number(2),
+                                                             Expr::Number(3)))));
+    }
+
+    #[test]
+    fn test_parse_direct_invalid() {
+        let tokens = tokens("100h");
+        let state = ParserState::new(&tokens);
+        let result = state.parse_operand();
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_branch_target_expression() {
+        let tokens = tokens("djnz R2, loop + 2");
+        let state = ParserState::new(&tokens);
+        let result = state.parse_code_line();
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap().result,
+                   LineBody::CodeLine {
+                       operator: Operator::Djnz,
+                       operands: vec![at(Operand::Register(Register::R(2)), 6, 2),
+                                      at(Operand::Address(Expr::binary(BinaryOperator::Add,
+                                                                       Expr::Symbol("loop"
+                                                                           .to_string()),
+                                                                       Expr::Number(2))),
+                                         10,
+                                         8)],
+                   });
+    }
+
+    #[test]
+    fn test_branch_target_location() {
+        let tokens = tokens("sjmp $ - 2");
+        let state = ParserState::new(&tokens);
+        let result = state.parse_code_line();
+        assert_eq!(result.unwrap().result,
+                   LineBody::CodeLine {
+                       operator: Operator::Sjmp,
+                       operands: vec![at(Operand::Address(Expr::binary(BinaryOperator::Sub,
+                                                                       Expr::Location,
+                                                                       Expr::Number(2))),
+                                         6,
+                                         5)],
+                   });
+    }
+
+    #[test]
+    fn test_branch_target_number() {
+        let tokens = tokens("ljmp 1234h");
+        let state = ParserState::new(&tokens);
+        let result = state.parse_code_line();
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap().result,
+                   LineBody::CodeLine {
+                       operator: Operator::Ljmp,
+                       operands: vec![at(Operand::Address(Expr::Number(0x1234)), 6, 5)],
+                   });
+    }
+
+    #[test]
+    fn test_branch_target_immediate() {
+        let tokens = tokens("sjmp #loop");
+        let state = ParserState::new(&tokens);
+        let result = state.parse_code_line();
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap().result,
+                   LineBody::CodeLine {
+                       operator: Operator::Sjmp,
+                       operands: vec![at(Operand::Immediate(Expr::Symbol("loop".to_string())),
+                                         6,
+                                         5)],
+                   });
+    }
+
+    #[test]
+    fn test_define_words_expression() {
+        let tokens = tokens("dw table + 2, 1000h - 1");
+        let state = ParserState::new(&tokens);
+        let result = state.parse_value_def();
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap().result,
+                   LineBody::ValueDefinition {
+                       values: vec![at(Value::Word(Expr::binary(BinaryOperator::Add,
+                                                                Expr::Symbol("table".to_owned()),
+                                                                Expr::Number(2))),
+                                       4,
+                                       9),
+                                    at(Value::Word(Expr::binary(BinaryOperator::Sub,
+                                                                Expr::Number(0x1000),
+                                                                Expr::Number(1))),
+                                       15,
+                                       9)],
+                   });
+    }
+
+    #[test]
+    fn test_org_equ_expression() {
+        let tokens = tokens("org 100h + 3\nsize equ 4 * 4");
+        let parsed_program = ParserState::parse(tokens, "test.asm");
+        assert!(parsed_program.is_ok());
+        let lines = parsed_program.unwrap().lines;
+        assert_eq!(lines[0].node,
+                   Line::OrgLine {
+                       address: Expr::binary(BinaryOperator::Add,
+                                             Expr::Number(0x100),
+                                             Expr::Number(3)),
+                   });
+        assert_eq!(lines[1].node,
+                   Line::EquDef {
+                       id: "size".to_owned(),
+                       value: Expr::binary(BinaryOperator::Mul, Expr::Number(4), Expr::Number(4)),
+                   });
     }
 
     #[test]
@@ -898,7 +1699,146 @@ mod test {
         assert!(tokens.is_ok());
         let tokens = tokens.unwrap();
 
-        let parsed_program = ParserState::parse(tokens);
+        let parsed_program = ParserState::parse(tokens, "test.asm");
         assert!(parsed_program.is_ok());
     }
+
+    #[test]
+    fn test_contextual_words() {
+        let tokens = tokens("org: mov tmod, #1\n\
+                             ret: sjmp ret\n\
+                             mod equ org mod 4\n\
+                             org 100h\n\
+                             db mod, tmod");
+        let lines: Vec<_> = ParserState::parse(tokens, "test.asm")
+            .unwrap()
+            .lines
+            .into_iter()
+            .map(|line| line.node)
+            .collect();
+        assert_eq!(lines.len(), 5);
+        match lines[0] {
+            Line::ProgramLine { label: Some(ref label), body: Some(ref body) } => {
+                assert_eq!(label.node, Label("org".to_owned()));
+                match body.node {
+                    LineBody::CodeLine { operator, ref operands } => {
+                        assert_eq!(operator, Operator::Mov);
+                        assert_eq!(operands[0].node, Operand::Direct(Expr::Number(0x89)));
+                    }
+                    ref other => panic!("Expected a code line, got {:?}", other),
+                }
+            }
+            ref other => panic!("Expected a labelled code line, got {:?}", other),
+        }
+        match lines[1] {
+            Line::ProgramLine { label: Some(ref label), .. } => {
+                assert_eq!(label.node, Label("ret".to_owned()))
+            }
+            ref other => panic!("Expected a labelled line, got {:?}", other),
+        }
+        assert_eq!(lines[2],
+                   Line::EquDef {
+                       id: "mod".to_owned(),
+                       value: Expr::binary(BinaryOperator::Mod,
+                                           Expr::Symbol("org".to_owned()),
+                                           Expr::Number(4)),
+                   });
+        assert_eq!(lines[3], Line::OrgLine { address: Expr::Number(0x100) });
+    }
+
+    #[test]
+    fn test_predefined_names() {
+        let tokens = tokens("B equ 5\n\
+                             TMOD: nop\n\
+                             r1: nop\n\
+                             cy equ 1\n\
+                             ret_val: nop");
+        let errors = ParserState::parse(tokens, "test.asm").unwrap_err();
+        let names: Vec<_> = errors.iter()
+            .map(|e| match e.error {
+                     ParseError::PredefinedName(ref name, _) => &name[..],
+                     ref other => panic!("Expected a predefined name error, got {:?}", other),
+                 })
+            .collect();
+        assert_eq!(names, vec!["B", "TMOD", "r1", "cy"]);
+    }
+
+    #[test]
+    fn test_error_recovery() {
+        let tokens = tokens("mov a, @20h\n\
+                             nop\n\
+                             org\n\
+                             start: db 300\n\
+                             mov a, 20h 21h\n\
+                             ret");
+        let errors = ParserState::parse(tokens, "test.asm").unwrap_err();
+        assert_eq!(errors.len(), 4);
+        // errors are reported at the token they were found at
+        assert_eq!((errors[0].span.row, errors[0].span.column, errors[0].span.length),
+                   (1, 8, 4));
+        match errors[0].error {
+            ParseError::InvalidOperand(Operator::Mov, span) => {
+                assert_eq!((span.start().column(), span.end().column()), (8, 12))
+            }
+            ref other => panic!("Expected an invalid operand error, got {:?}", other),
+        }
+        assert_eq!(errors[0].error.to_string(), "Invalid operand for MOV");
+        assert_eq!(errors[1].span.row, 3);
+        match errors[1].error {
+            ParseError::ExpectedExpression(_) => (),
+            ref other => panic!("Expected a missing expression error, got {:?}", other),
+        }
+        assert_eq!(errors[2].span.row, 4);
+        match errors[2].error {
+            ParseError::InvalidByte(300, pos) => assert_eq!(pos.column(), 11),
+            ref other => panic!("Expected an invalid byte error, got {:?}", other),
+        }
+        assert_eq!(errors[3].span.row, 5);
+        match errors[3].error {
+            ParseError::ExpectedNewline(pos) => assert_eq!(pos.column(), 12),
+            ref other => panic!("Expected a missing newline error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_error_recovery_invalid_tokens() {
+        let (tokens, tokenizer_errors) = Tokenizer::tokenize_lossy("mov a, ?12\n\
+                                                                   db \"abc\n\
+                                                                   mov a, #'\\q'\n\
+                                                                   mov a, @20h");
+        assert_eq!(tokenizer_errors.len(), 3);
+        let errors = ParserState::parse(tokens, "test.asm").unwrap_err();
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].span.row, 4);
+    }
+
+    #[test]
+    fn test_spans() {
+        let tokens = tokens("start:\tmov A, 20h ; comment\n  ret\n");
+        let lines = ParserState::parse(tokens, "test.asm").unwrap().lines;
+        let span = |row, column, length| {
+            SourceSpan {
+                file: Rc::from("test.asm"),
+                row,
+                column,
+                length,
+            }
+        };
+        assert_eq!(lines[0].span, span(1, 1, 17));
+        match lines[0].node {
+            Line::ProgramLine { label: Some(ref label), body: Some(ref body) } => {
+                assert_eq!(label.span, span(1, 1, 6));
+                assert_eq!(body.span, span(1, 8, 10));
+                match body.node {
+                    LineBody::CodeLine { ref operands, .. } => {
+                        assert_eq!(operands[0].span, span(1, 12, 1));
+                        assert_eq!(operands[1].span, span(1, 15, 3));
+                    }
+                    ref other => panic!("Expected a code line, got {:?}", other),
+                }
+            }
+            ref other => panic!("Expected a labelled program line, got {:?}", other),
+        }
+        assert_eq!(lines[1].span, span(2, 3, 3));
+    }
 }
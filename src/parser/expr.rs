@@ -0,0 +1,241 @@
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UnaryOperator {
+    Neg,
+    Not,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BinaryOperator {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Shl,
+    Shr,
+    And,
+    Or,
+    Xor,
+}
+
+/// A numeric expression, as written in an operand or a directive
+#[derive(Clone, Debug, PartialEq)]
+pub enum Expr {
+    Number(i32),
+    Symbol(String),
+    /// `$` - the address of the statement the expression is in
+    Location,
+    Unary(UnaryOperator, Box<Expr>),
+    Binary(BinaryOperator, Box<Expr>, Box<Expr>),
+    /// `byte.bit` - the bit address of a bit within a bit-addressable byte
+    Bit(Box<Expr>, Box<Expr>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum EvalError {
+    UnknownSymbol(String),
+    DivisionByZero,
+    /// The byte of a `byte.bit` isn't bit-addressable
+    InvalidBitAddress(i32),
+    /// The bit of a `byte.bit` isn't 0..7
+    InvalidBitNumber(i32),
+}
+
+impl Expr {
+    pub fn unary(op: UnaryOperator, expr: Expr) -> Expr {
+        Expr::Unary(op, Box::new(expr))
+    }
+
+    pub fn binary(op: BinaryOperator, left: Expr, right: Expr) -> Expr {
+        Expr::Binary(op, Box::new(left), Box::new(right))
+    }
+
+    pub fn bit(byte: Expr, bit: Expr) -> Expr {
+        Expr::Bit(Box::new(byte), Box::new(bit))
+    }
+
+    /// Evaluates the expression, resolving symbols with `lookup`
+    pub fn eval<F>(&self, lookup: &F) -> Result<i32, EvalError>
+        where F: Fn(&str) -> Option<i32>
+    {
+        match *self {
+            Expr::Number(n) => Ok(n),
+            Expr::Symbol(ref s) => lookup(s).ok_or_else(|| EvalError::UnknownSymbol(s.clone())),
+            Expr::Location => lookup("$").ok_or_else(|| EvalError::UnknownSymbol("$".to_owned())),
+            Expr::Unary(op, ref expr) => {
+                let value = expr.eval(lookup)?;
+                match op {
+                    UnaryOperator::Neg => Ok(value.wrapping_neg()),
+                    UnaryOperator::Not => Ok(!value),
+                }
+            }
+            Expr::Binary(op, ref left, ref right) => {
+                let left = left.eval(lookup)?;
+                let right = right.eval(lookup)?;
+                Self::apply(op, left, right)
+            }
+            Expr::Bit(ref byte, ref bit) => {
+                let byte = byte.eval(lookup)?;
+                let bit = bit.eval(lookup)?;
+                Self::bit_address(byte, bit)
+            }
+        }
+    }
+
+    /// Replaces the name of every symbol in the expression with `rename(name)`. The location
+    /// counter becomes the symbol `rename("$")`, so that it can be bound to an address.
+    pub fn rename_symbols<F>(self, rename: &F) -> Expr
+        where F: Fn(&str) -> String
+    {
+        match self {
+            Expr::Number(n) => Expr::Number(n),
+            Expr::Symbol(s) => Expr::Symbol(rename(&s)),
+            Expr::Location => Expr::Symbol(rename("$")),
+            Expr::Unary(op, expr) => Expr::unary(op, expr.rename_symbols(rename)),
+            Expr::Binary(op, left, right) => {
+                Expr::binary(op, left.rename_symbols(rename), right.rename_symbols(rename))
+            }
+            Expr::Bit(byte, bit) => Expr::bit(byte.rename_symbols(rename), bit.rename_symbols(rename)),
+        }
+    }
+
+    /// Returns whether the expression is a bit address: a `byte.bit` or a predefined bit name
+    pub fn is_bit(&self) -> bool {
+        matches!(*self, Expr::Bit(_, _))
+    }
+
+    /// Returns the value of the expression if it doesn't depend on any symbols
+    pub fn constant(&self) -> Option<i32> {
+        self.eval(&|_| None).ok()
+    }
+
+    fn apply(op: BinaryOperator, left: i32, right: i32) -> Result<i32, EvalError> {
+        match op {
+            BinaryOperator::Add => Ok(left.wrapping_add(right)),
+            BinaryOperator::Sub => Ok(left.wrapping_sub(right)),
+            BinaryOperator::Mul => Ok(left.wrapping_mul(right)),
+            BinaryOperator::Div => {
+                if right == 0 {
+                    Err(EvalError::DivisionByZero)
+                } else {
+                    Ok(left.wrapping_div(right))
+                }
+            }
+            BinaryOperator::Mod => {
+                if right == 0 {
+                    Err(EvalError::DivisionByZero)
+                } else {
+                    Ok(left.wrapping_rem(right))
+                }
+            }
+            BinaryOperator::Shl => {
+                if (0..32).contains(&right) {
+                    Ok(left << right)
+                } else {
+                    Ok(0)
+                }
+            }
+            BinaryOperator::Shr => {
+                if (0..32).contains(&right) {
+                    Ok(left >> right)
+                } else {
+                    Ok(0)
+                }
+            }
+            BinaryOperator::And => Ok(left & right),
+            BinaryOperator::Or => Ok(left | right),
+            BinaryOperator::Xor => Ok(left ^ right),
+        }
+    }
+
+    /// The bit address of a bit in the bit-addressable RAM at 20h..2Fh, or in an SFR whose
+    /// address is a multiple of 8
+    fn bit_address(addr: i32, bit_num: i32) -> Result<i32, EvalError> {
+        if !(0..8).contains(&bit_num) {
+            return Err(EvalError::InvalidBitNumber(bit_num));
+        }
+        if (0x20..0x30).contains(&addr) {
+            Ok((addr - 0x20) * 8 + bit_num)
+        } else if (0x80..0x100).contains(&addr) && addr % 8 == 0 {
+            Ok(addr + bit_num)
+        } else {
+            Err(EvalError::InvalidBitAddress(addr))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn num(n: i32) -> Expr {
+        Expr::Number(n)
+    }
+
+    #[test]
+    fn test_eval_arithmetic() {
+        // (10 - 1) * 3 MOD 4
+        let expr = Expr::binary(BinaryOperator::Mod,
+                                Expr::binary(BinaryOperator::Mul,
+                                             Expr::binary(BinaryOperator::Sub, num(10), num(1)),
+                                             num(3)),
+                                num(4));
+        assert_eq!(expr.constant(), Some(3));
+    }
+
+    #[test]
+    fn test_eval_bitwise() {
+        let expr = Expr::binary(BinaryOperator::Or,
+                                Expr::binary(BinaryOperator::Shl, num(1), num(4)),
+                                Expr::unary(UnaryOperator::Not, num(-4)));
+        assert_eq!(expr.constant(), Some(0x13));
+    }
+
+    #[test]
+    fn test_eval_symbol() {
+        let expr = Expr::binary(BinaryOperator::Add,
+                                Expr::Symbol("table".to_owned()),
+                                num(2));
+        assert_eq!(expr.constant(), None);
+        let result = expr.eval(&|s| if s == "table" { Some(0x100) } else { None });
+        assert_eq!(result, Ok(0x102));
+    }
+
+    #[test]
+    fn test_rename_symbols() {
+        let expr = Expr::binary(BinaryOperator::Add,
+                                Expr::Symbol("table".to_owned()),
+                                Expr::bit(Expr::Symbol("Flags".to_owned()), num(1)));
+        assert_eq!(expr.rename_symbols(&|s| s.to_uppercase()),
+                   Expr::binary(BinaryOperator::Add,
+                                Expr::Symbol("TABLE".to_owned()),
+                                Expr::bit(Expr::Symbol("FLAGS".to_owned()), num(1))));
+    }
+
+    #[test]
+    fn test_eval_division_by_zero() {
+        let expr = Expr::binary(BinaryOperator::Div, num(1), num(0));
+        assert_eq!(expr.eval(&|_| None), Err(EvalError::DivisionByZero));
+    }
+
+    #[test]
+    fn test_eval_bit() {
+        assert_eq!(Expr::bit(num(0x21), num(3)).constant(), Some(0x0B));
+        assert_eq!(Expr::bit(num(0x90), num(2)).constant(), Some(0x92));
+        assert_eq!(Expr::bit(num(0x40), num(2)).eval(&|_| None),
+                   Err(EvalError::InvalidBitAddress(0x40)));
+    }
+
+    #[test]
+    fn test_eval_bit_invalid() {
+        // SP and DPL aren't bit-addressable, even though they're SFRs
+        assert_eq!(Expr::bit(num(0x81), num(2)).eval(&|_| None),
+                   Err(EvalError::InvalidBitAddress(0x81)));
+        assert_eq!(Expr::bit(num(0x82), num(1)).eval(&|_| None),
+                   Err(EvalError::InvalidBitAddress(0x82)));
+        assert_eq!(Expr::bit(num(0x20), num(8)).eval(&|_| None),
+                   Err(EvalError::InvalidBitNumber(8)));
+        assert_eq!(Expr::bit(num(0x88), num(-1)).eval(&|_| None),
+                   Err(EvalError::InvalidBitNumber(-1)));
+    }
+}
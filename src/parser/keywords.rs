@@ -1,5 +1,14 @@
-use regex::Regex;
-use std::str::FromStr;
+use std::fmt::{self, Display};
+use std::str::{self, FromStr};
+
+/// Lowercases a name into `buf`, so that it can be looked up without allocating. Returns `None`
+/// if it's longer than any of the predefined names.
+fn lowercase<'a>(s: &str, buf: &'a mut [u8; 8]) -> Option<&'a str> {
+    let bytes = buf.get_mut(..s.len())?;
+    bytes.copy_from_slice(s.as_bytes());
+    bytes.make_ascii_lowercase();
+    str::from_utf8(bytes).ok()
+}
 
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Operator {
@@ -54,7 +63,8 @@ impl FromStr for Operator {
     type Err = ();
 
     fn from_str(s: &str) -> Result<Operator, ()> {
-        match s.to_lowercase().as_ref() {
+        let mut buf = [0; 8];
+        match lowercase(s, &mut buf).ok_or(())? {
             "acall" => Ok(Operator::Acall),
             "add" => Ok(Operator::Add),
             "addc" => Ok(Operator::Addc),
@@ -64,7 +74,7 @@ impl FromStr for Operator {
             "cjne" => Ok(Operator::Cjne),
             "clr" => Ok(Operator::Clr),
             "cpl" => Ok(Operator::Cpl),
-            "da," => Ok(Operator::Da),
+            "da" => Ok(Operator::Da),
             "dec" => Ok(Operator::Dec),
             "div" => Ok(Operator::Div),
             "djnz" => Ok(Operator::Djnz),
@@ -105,6 +115,26 @@ impl FromStr for Operator {
     }
 }
 
+impl Display for Operator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", format!("{:?}", self).to_uppercase())
+    }
+}
+
+impl Operator {
+    /// Returns the index of the operand holding the code address, for jumps and calls
+    pub fn target_operand(&self) -> Option<usize> {
+        match *self {
+            Operator::Acall | Operator::Ajmp | Operator::Call | Operator::Jc | Operator::Jmp |
+            Operator::Jnc | Operator::Jnz | Operator::Jz | Operator::Lcall | Operator::Ljmp |
+            Operator::Sjmp => Some(0),
+            Operator::Djnz | Operator::Jb | Operator::Jbc | Operator::Jnb => Some(1),
+            Operator::Cjne => Some(2),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub enum Register {
     R(u8),
@@ -112,27 +142,29 @@ pub enum Register {
     C,
     PC,
     DPTR,
+    /// The A and B pair, read by `MUL AB` and `DIV AB`
+    AB,
 }
 
 impl FromStr for Register {
     type Err = ();
 
     fn from_str(s: &str) -> Result<Register, ()> {
-        let re = Regex::new(r"^R(\d)$").unwrap();
-        if let Some(caps) = re.captures(s) {
-            let reg_num: u8 = caps.at(1).unwrap().parse().unwrap();
-            if reg_num < 8 {
-                return Ok(Register::R(reg_num));
-            } else {
-                return Err(());
-            }
+        let mut buf = [0; 8];
+        let s = lowercase(s, &mut buf).ok_or(())?;
+        if s.len() == 2 && s.starts_with('r') {
+            return match s[1..].parse() {
+                Ok(reg_num) if reg_num < 8 => Ok(Register::R(reg_num)),
+                _ => Err(()),
+            };
         }
 
-        match s.to_lowercase().as_ref() {
+        match s {
             "a" => Ok(Register::A),
             "c" => Ok(Register::C),
             "pc" => Ok(Register::PC),
             "dptr" => Ok(Register::DPTR),
+            "ab" => Ok(Register::AB),
             _ => Err(()),
         }
     }
@@ -148,7 +180,8 @@ impl FromStr for Definition {
     type Err = ();
 
     fn from_str(s: &str) -> Result<Definition, ()> {
-        match s.to_lowercase().as_ref() {
+        let mut buf = [0; 8];
+        match lowercase(s, &mut buf).ok_or(())? {
             "db" => Ok(Definition::DefineByte),
             "dw" => Ok(Definition::DefineWord),
             _ => Err(()),
@@ -200,17 +233,16 @@ impl FromStr for DirectLocation {
     type Err = ();
 
     fn from_str(s: &str) -> Result<DirectLocation, ()> {
-        let re = Regex::new(r"^p(\d)$").unwrap();
-        if let Some(caps) = re.captures(&s.to_lowercase()) {
-            let reg_num: u8 = caps.at(1).unwrap().parse().unwrap();
-            if reg_num < 7 {
-                return Ok(DirectLocation::Port(reg_num));
-            } else {
-                return Err(());
-            }
+        let mut buf = [0; 8];
+        let s = lowercase(s, &mut buf).ok_or(())?;
+        if s.len() == 2 && s.starts_with('p') {
+            return match s[1..].parse() {
+                Ok(port_num) if port_num < 7 => Ok(DirectLocation::Port(port_num)),
+                _ => Err(()),
+            };
         }
 
-        match s.to_lowercase().as_ref() {
+        match s {
             "sp" => Ok(DirectLocation::SP),
             "dpl" => Ok(DirectLocation::DPL),
             "dph" => Ok(DirectLocation::DPH),
@@ -305,20 +337,235 @@ impl DirectLocation {
     }
 }
 
+/// The named bits of the bit-addressable SFRs. Names too generic to keep from user symbols,
+/// like P (PSW.0), WDT or the ADCON bits, are left out; those bits are written as `byte.bit`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BitLocation {
+    IT0,
+    IE0,
+    IT1,
+    IE1,
+    TR0,
+    TF0,
+    TR1,
+    TF1,
+    RI,
+    TI,
+    RB8,
+    TB8,
+    REN,
+    SM2,
+    SM1,
+    SM0,
+    EX0,
+    ET0,
+    EX1,
+    ET1,
+    ES,
+    ET2,
+    EA,
+    EADC,
+    EX2,
+    EX3,
+    EX4,
+    EX5,
+    EX6,
+    SWDT,
+    EXEN2,
+    IADC,
+    IEX2,
+    IEX3,
+    IEX4,
+    IEX5,
+    IEX6,
+    TF2,
+    EXF2,
+    T2I0,
+    T2I1,
+    T2CM,
+    T2R0,
+    T2R1,
+    I2FR,
+    I3FR,
+    T2PS,
+    F1,
+    OV,
+    RS0,
+    RS1,
+    F0,
+    AC,
+    CY,
+}
+
+impl FromStr for BitLocation {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<BitLocation, ()> {
+        let mut buf = [0; 8];
+        match lowercase(s, &mut buf).ok_or(())? {
+            "it0" => Ok(BitLocation::IT0),
+            "ie0" => Ok(BitLocation::IE0),
+            "it1" => Ok(BitLocation::IT1),
+            "ie1" => Ok(BitLocation::IE1),
+            "tr0" => Ok(BitLocation::TR0),
+            "tf0" => Ok(BitLocation::TF0),
+            "tr1" => Ok(BitLocation::TR1),
+            "tf1" => Ok(BitLocation::TF1),
+            "ri" => Ok(BitLocation::RI),
+            "ti" => Ok(BitLocation::TI),
+            "rb8" => Ok(BitLocation::RB8),
+            "tb8" => Ok(BitLocation::TB8),
+            "ren" => Ok(BitLocation::REN),
+            "sm2" => Ok(BitLocation::SM2),
+            "sm1" => Ok(BitLocation::SM1),
+            "sm0" => Ok(BitLocation::SM0),
+            "ex0" => Ok(BitLocation::EX0),
+            "et0" => Ok(BitLocation::ET0),
+            "ex1" => Ok(BitLocation::EX1),
+            "et1" => Ok(BitLocation::ET1),
+            "es" => Ok(BitLocation::ES),
+            "et2" => Ok(BitLocation::ET2),
+            // the 80C515 calls it EAL
+            "ea" | "eal" => Ok(BitLocation::EA),
+            "eadc" => Ok(BitLocation::EADC),
+            "ex2" => Ok(BitLocation::EX2),
+            "ex3" => Ok(BitLocation::EX3),
+            "ex4" => Ok(BitLocation::EX4),
+            "ex5" => Ok(BitLocation::EX5),
+            "ex6" => Ok(BitLocation::EX6),
+            "swdt" => Ok(BitLocation::SWDT),
+            "exen2" => Ok(BitLocation::EXEN2),
+            "iadc" => Ok(BitLocation::IADC),
+            "iex2" => Ok(BitLocation::IEX2),
+            "iex3" => Ok(BitLocation::IEX3),
+            "iex4" => Ok(BitLocation::IEX4),
+            "iex5" => Ok(BitLocation::IEX5),
+            "iex6" => Ok(BitLocation::IEX6),
+            "tf2" => Ok(BitLocation::TF2),
+            "exf2" => Ok(BitLocation::EXF2),
+            "t2i0" => Ok(BitLocation::T2I0),
+            "t2i1" => Ok(BitLocation::T2I1),
+            "t2cm" => Ok(BitLocation::T2CM),
+            "t2r0" => Ok(BitLocation::T2R0),
+            "t2r1" => Ok(BitLocation::T2R1),
+            "i2fr" => Ok(BitLocation::I2FR),
+            "i3fr" => Ok(BitLocation::I3FR),
+            "t2ps" => Ok(BitLocation::T2PS),
+            "f1" => Ok(BitLocation::F1),
+            "ov" => Ok(BitLocation::OV),
+            "rs0" => Ok(BitLocation::RS0),
+            "rs1" => Ok(BitLocation::RS1),
+            "f0" => Ok(BitLocation::F0),
+            "ac" => Ok(BitLocation::AC),
+            "cy" => Ok(BitLocation::CY),
+            _ => Err(()),
+        }
+    }
+}
+
+impl BitLocation {
+    /// The SFR holding the bit, along with the number of the bit within it
+    pub fn location(&self) -> (DirectLocation, u8) {
+        match *self {
+            BitLocation::IT0 => (DirectLocation::TCON, 0),
+            BitLocation::IE0 => (DirectLocation::TCON, 1),
+            BitLocation::IT1 => (DirectLocation::TCON, 2),
+            BitLocation::IE1 => (DirectLocation::TCON, 3),
+            BitLocation::TR0 => (DirectLocation::TCON, 4),
+            BitLocation::TF0 => (DirectLocation::TCON, 5),
+            BitLocation::TR1 => (DirectLocation::TCON, 6),
+            BitLocation::TF1 => (DirectLocation::TCON, 7),
+            BitLocation::RI => (DirectLocation::SCON, 0),
+            BitLocation::TI => (DirectLocation::SCON, 1),
+            BitLocation::RB8 => (DirectLocation::SCON, 2),
+            BitLocation::TB8 => (DirectLocation::SCON, 3),
+            BitLocation::REN => (DirectLocation::SCON, 4),
+            BitLocation::SM2 => (DirectLocation::SCON, 5),
+            BitLocation::SM1 => (DirectLocation::SCON, 6),
+            BitLocation::SM0 => (DirectLocation::SCON, 7),
+            BitLocation::EX0 => (DirectLocation::IEN0, 0),
+            BitLocation::ET0 => (DirectLocation::IEN0, 1),
+            BitLocation::EX1 => (DirectLocation::IEN0, 2),
+            BitLocation::ET1 => (DirectLocation::IEN0, 3),
+            BitLocation::ES => (DirectLocation::IEN0, 4),
+            BitLocation::ET2 => (DirectLocation::IEN0, 5),
+            BitLocation::EA => (DirectLocation::IEN0, 7),
+            BitLocation::EADC => (DirectLocation::IEN1, 0),
+            BitLocation::EX2 => (DirectLocation::IEN1, 1),
+            BitLocation::EX3 => (DirectLocation::IEN1, 2),
+            BitLocation::EX4 => (DirectLocation::IEN1, 3),
+            BitLocation::EX5 => (DirectLocation::IEN1, 4),
+            BitLocation::EX6 => (DirectLocation::IEN1, 5),
+            BitLocation::SWDT => (DirectLocation::IEN1, 6),
+            BitLocation::EXEN2 => (DirectLocation::IEN1, 7),
+            BitLocation::IADC => (DirectLocation::IRCON, 0),
+            BitLocation::IEX2 => (DirectLocation::IRCON, 1),
+            BitLocation::IEX3 => (DirectLocation::IRCON, 2),
+            BitLocation::IEX4 => (DirectLocation::IRCON, 3),
+            BitLocation::IEX5 => (DirectLocation::IRCON, 4),
+            BitLocation::IEX6 => (DirectLocation::IRCON, 5),
+            BitLocation::TF2 => (DirectLocation::IRCON, 6),
+            BitLocation::EXF2 => (DirectLocation::IRCON, 7),
+            BitLocation::T2I0 => (DirectLocation::T2CON, 0),
+            BitLocation::T2I1 => (DirectLocation::T2CON, 1),
+            BitLocation::T2CM => (DirectLocation::T2CON, 2),
+            BitLocation::T2R0 => (DirectLocation::T2CON, 3),
+            BitLocation::T2R1 => (DirectLocation::T2CON, 4),
+            BitLocation::I2FR => (DirectLocation::T2CON, 5),
+            BitLocation::I3FR => (DirectLocation::T2CON, 6),
+            BitLocation::T2PS => (DirectLocation::T2CON, 7),
+            BitLocation::F1 => (DirectLocation::PSW, 1),
+            BitLocation::OV => (DirectLocation::PSW, 2),
+            BitLocation::RS0 => (DirectLocation::PSW, 3),
+            BitLocation::RS1 => (DirectLocation::PSW, 4),
+            BitLocation::F0 => (DirectLocation::PSW, 5),
+            BitLocation::AC => (DirectLocation::PSW, 6),
+            BitLocation::CY => (DirectLocation::PSW, 7),
+        }
+    }
+
+    pub fn get_addr(&self) -> u8 {
+        let (byte, bit) = self.location();
+        // the bit-addressable SFRs lie at multiples of 8, so their bits follow the byte address
+        byte.get_addr() + bit
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub enum Keyword {
     Org,
     Equ,
+    Mod,
+    Shl,
+    Shr,
+    And,
+    Or,
+    Xor,
+    Not,
 }
 
 impl FromStr for Keyword {
     type Err = ();
 
     fn from_str(s: &str) -> Result<Keyword, ()> {
-        match s.to_lowercase().as_ref() {
+        let mut buf = [0; 8];
+        match lowercase(s, &mut buf).ok_or(())? {
             "org" => Ok(Keyword::Org),
             "equ" => Ok(Keyword::Equ),
+            "mod" => Ok(Keyword::Mod),
+            "shl" => Ok(Keyword::Shl),
+            "shr" => Ok(Keyword::Shr),
+            "and" => Ok(Keyword::And),
+            "or" => Ok(Keyword::Or),
+            "xor" => Ok(Keyword::Xor),
+            "not" => Ok(Keyword::Not),
             _ => Err(()),
         }
     }
 }
+
+impl Display for Keyword {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", format!("{:?}", self).to_uppercase())
+    }
+}
@@ -1,32 +1,128 @@
-use super::keywords::{DirectLocation, Keyword, Operator};
+use super::keywords::{BitLocation, DirectLocation, Keyword, Operator};
+use std::collections::VecDeque;
 use std::error;
 use std::fmt::Display;
-use std::io::{self, Write};
+use std::io::{self, BufRead, BufReader, Read, Write};
 use std::mem;
 use std::str;
 
+/// A place in the source: its row and column, counted in characters from 1, and its offset in
+/// bytes from the start of the source
 #[derive(Clone, Copy, Debug, PartialEq)]
 pub struct Position {
     row: usize,
     column: usize,
+    offset: usize,
+}
+
+/// The part of the source a token was read from
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Span {
+    start: Position,
+    end: Position,
+}
+
+impl Position {
+    pub fn row(&self) -> usize {
+        self.row
+    }
+
+    pub fn column(&self) -> usize {
+        self.column
+    }
+
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+}
+
+impl Span {
+    /// The position of the first character of the token
+    pub fn start(&self) -> Position {
+        self.start
+    }
+
+    /// The position right after the last character of the token
+    pub fn end(&self) -> Position {
+        self.end
+    }
+
+    /// The length of the token in bytes
+    pub fn len(&self) -> usize {
+        self.end.offset - self.start.offset
+    }
+
+    /// Whether the span covers no bytes of the source
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The span from the start of this one to the end of `other`
+    pub fn to(&self, other: &Span) -> Span {
+        Span {
+            start: self.start,
+            end: other.end,
+        }
+    }
+}
+
+impl Display for Position {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "row {}, column {}", self.row, self.column)
+    }
 }
 
 /// An enumeration listing possible tokens
 #[derive(Clone, Debug, PartialEq)]
 pub enum Token {
-    Identifier(String, Position),
-    Operator(Operator, Position),
-    DirectLocation(DirectLocation, Position),
-    Keyword(Keyword, Position),
-    Number(String, Position),
-    String(String, Position),
-    Colon(Position),
-    Comma(Position),
-    Dot(Position),
-    At(Position),
-    Hash(Position),
-    Plus(Position),
-    Newline(Position),
+    /// A word; whether it's a mnemonic, a keyword, a register or a symbol depends on where it
+    /// appears in the statement, so telling them apart is left to the parser
+    Identifier(String, Span),
+    Number(String, Span),
+    /// `$` on its own - the location counter
+    Dollar(Span),
+    /// A string in single or double quotes, as the bytes it stands for
+    String(Vec<u8>, Span),
+    Colon(Span),
+    Comma(Span),
+    Dot(Span),
+    At(Span),
+    Hash(Span),
+    Plus(Span),
+    Minus(Span),
+    Asterisk(Span),
+    Slash(Span),
+    LeftParen(Span),
+    RightParen(Span),
+    Ampersand(Span),
+    Pipe(Span),
+    Caret(Span),
+    Tilde(Span),
+    Percent(Span),
+    Exclamation(Span),
+    Equals(Span),
+    Less(Span),
+    Greater(Span),
+    LessEqual(Span),
+    GreaterEqual(Span),
+    /// `<>` or `!=`
+    NotEqual(Span),
+    ShiftLeft(Span),
+    ShiftRight(Span),
+    Newline(Span),
+    /// A part of the source that couldn't be tokenized
+    Error(Span),
+    /// Spaces and tabs; only kept by `Tokenizer::tokenize_lossless`
+    Whitespace(Span),
+    /// The text of a comment, after the semicolon; only kept by `Tokenizer::tokenize_lossless`
+    Comment(String, Span),
+}
+
+/// A token along with its spelling in the source
+#[derive(Clone, Debug, PartialEq)]
+pub struct SourceToken {
+    pub token: Token,
+    pub text: String,
 }
 
 impl Token {
@@ -86,6 +182,10 @@ impl Token {
         }
     }
 
+    pub fn is_slash(&self) -> bool {
+        matches!(*self, Token::Slash(_))
+    }
+
     pub fn is_newline(&self) -> bool {
         match *self {
             Token::Newline(_) => true,
@@ -93,13 +193,24 @@ impl Token {
         }
     }
 
+    pub fn is_error(&self) -> bool {
+        matches!(*self, Token::Error(_))
+    }
+
+    /// Returns whether the token is whitespace or a comment
+    pub fn is_trivia(&self) -> bool {
+        matches!(*self, Token::Whitespace(_) | Token::Comment(_, _))
+    }
+
     pub fn get_position(&self) -> Position {
+        self.get_span().start()
+    }
+
+    pub fn get_span(&self) -> Span {
         match *self {
             Token::Identifier(_, p) => p,
-            Token::Operator(_, p) => p,
-            Token::DirectLocation(_, p) => p,
-            Token::Keyword(_, p) => p,
             Token::Number(_, p) => p,
+            Token::Dollar(p) => p,
             Token::String(_, p) => p,
             Token::Colon(p) => p,
             Token::Comma(p) => p,
@@ -107,7 +218,56 @@ impl Token {
             Token::At(p) => p,
             Token::Hash(p) => p,
             Token::Plus(p) => p,
+            Token::Minus(p) => p,
+            Token::Asterisk(p) => p,
+            Token::Slash(p) => p,
+            Token::LeftParen(p) => p,
+            Token::RightParen(p) => p,
+            Token::Ampersand(p) => p,
+            Token::Pipe(p) => p,
+            Token::Caret(p) => p,
+            Token::Tilde(p) => p,
+            Token::Percent(p) => p,
+            Token::Exclamation(p) => p,
+            Token::Equals(p) => p,
+            Token::Less(p) => p,
+            Token::Greater(p) => p,
+            Token::LessEqual(p) => p,
+            Token::GreaterEqual(p) => p,
+            Token::NotEqual(p) => p,
+            Token::ShiftLeft(p) => p,
+            Token::ShiftRight(p) => p,
             Token::Newline(p) => p,
+            Token::Error(p) => p,
+            Token::Whitespace(p) => p,
+            Token::Comment(_, p) => p,
+        }
+    }
+
+    /// The keyword spelled by the token, if it's a word that spells one
+    pub fn keyword(&self) -> Option<Keyword> {
+        self.word().and_then(|s| s.parse().ok())
+    }
+
+    /// The mnemonic spelled by the token, if it's a word that spells one
+    pub fn operator(&self) -> Option<Operator> {
+        self.word().and_then(|s| s.parse().ok())
+    }
+
+    /// The SFR named by the token, if it's a word that names one
+    pub fn direct_location(&self) -> Option<DirectLocation> {
+        self.word().and_then(|s| s.parse().ok())
+    }
+
+    /// The SFR bit named by the token, if it's a word that names one
+    pub fn bit_location(&self) -> Option<BitLocation> {
+        self.word().and_then(|s| s.parse().ok())
+    }
+
+    fn word(&self) -> Option<&str> {
+        match *self {
+            Token::Identifier(ref s, _) => Some(s),
+            _ => None,
         }
     }
 
@@ -126,9 +286,20 @@ enum TokenizerState {
     Ready,
     ReadingNumber(Vec<char>, Position),
     ReadingIdentifier(Vec<char>, Position),
-    ReadingString(Vec<char>, Position),
-    ReadingStringEscape(Vec<char>, Position),
-    ReadingComment,
+    /// Reading a string opened with the given quote
+    ReadingString(Vec<u8>, char, Position),
+    ReadingStringEscape(Vec<u8>, char, Position),
+    /// Reading the digits of a `\xNN` escape, along with the first digit, once it's been read
+    ReadingStringHex(Vec<u8>, char, Position, Option<u8>),
+    /// After a quote that either closes the string or is the first of a doubled quote
+    ReadingStringQuote(Vec<u8>, char, Position),
+    /// Reading a comment that started at the given position
+    ReadingComment(String, Position),
+    ReadingWhitespace(Position),
+    /// Reading a symbol that may be the first character of a two-character operator
+    ReadingSymbol(char, Position),
+    /// Skipping the rest of a malformed token that started at the given position
+    SkippingInvalid(Position),
     Invalid,
 }
 
@@ -137,23 +308,64 @@ pub struct Tokenizer {
     tokens: Vec<Token>,
     cur_pos: Position,
     state: TokenizerState,
+    /// Whether whitespace and comments become tokens
+    keep_trivia: bool,
+    /// Whether the string being read has an invalid escape in it, which has already been
+    /// reported, so that it ends up as a `Token::Error`
+    bad_escape: bool,
 }
 
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum TokenizerError {
-    UnexpectedCharacter(Position, char),
+    /// A character that can't start any token
+    IllegalCharacter(Position, char),
+    /// A string that isn't closed before the end of the line; points at the opening quote
+    UnterminatedString(Position),
+    /// An unknown escape sequence in a string; points at the character after the backslash
+    InvalidEscape(Position, char),
+    /// A character that can't appear in a number
+    MalformedNumber(Position, char),
+    /// The source couldn't be read; points at the place where reading stopped
+    Io(Position, io::ErrorKind),
 }
 
 impl Display for TokenizerError {
     fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
         match *self {
-            TokenizerError::UnexpectedCharacter(pos, c) => {
+            TokenizerError::IllegalCharacter(pos, c) => {
+                write!(f,
+                       "Illegal character {} at row {}, column {}",
+                       c,
+                       pos.row,
+                       pos.column)
+            }
+            TokenizerError::UnterminatedString(pos) => {
+                write!(f,
+                       "Unterminated string starting at row {}, column {}",
+                       pos.row,
+                       pos.column)
+            }
+            TokenizerError::InvalidEscape(pos, c) => {
                 write!(f,
-                       "Unexpected character {} at row {}, column {}",
+                       "Invalid escape sequence \\{} at row {}, column {}",
                        c,
                        pos.row,
                        pos.column)
             }
+            TokenizerError::MalformedNumber(pos, c) => {
+                write!(f,
+                       "Unexpected character {} in a number at row {}, column {}",
+                       c,
+                       pos.row,
+                       pos.column)
+            }
+            TokenizerError::Io(pos, kind) => {
+                write!(f,
+                       "Failed to read the source at row {}, column {}: {}",
+                       pos.row,
+                       pos.column,
+                       io::Error::from(kind))
+            }
         }
     }
 }
@@ -161,7 +373,11 @@ impl Display for TokenizerError {
 impl error::Error for TokenizerError {
     fn description(&self) -> &str {
         match *self {
-            TokenizerError::UnexpectedCharacter(_, _) => "unexpected character in tokenized stream",
+            TokenizerError::IllegalCharacter(_, _) => "illegal character in tokenized stream",
+            TokenizerError::UnterminatedString(_) => "unterminated string",
+            TokenizerError::InvalidEscape(_, _) => "invalid escape sequence in a string",
+            TokenizerError::MalformedNumber(_, _) => "malformed number",
+            TokenizerError::Io(_, _) => "failed to read the source",
         }
     }
 
@@ -174,17 +390,49 @@ impl Tokenizer {
     pub fn new() -> Tokenizer {
         Tokenizer {
             tokens: Vec::new(),
-            cur_pos: Position { row: 1, column: 1 },
+            cur_pos: Position {
+                row: 1,
+                column: 1,
+                offset: 0,
+            },
             state: TokenizerState::Ready,
+            keep_trivia: false,
+            bad_escape: false,
         }
     }
 
+    /// A tokenizer that also emits whitespace and comment tokens
+    pub fn with_trivia() -> Tokenizer {
+        Tokenizer { keep_trivia: true, ..Tokenizer::new() }
+    }
+
     fn get_tokens(self) -> Vec<Token> {
         self.tokens
     }
 
-    fn advance(&mut self) {
+    /// The span of the character `c` at the current position
+    fn char_span(&self, c: char) -> Span {
+        let mut end = self.cur_pos;
+        end.column += 1;
+        end.offset += c.len_utf8();
+        Span {
+            start: self.cur_pos,
+            end,
+        }
+    }
+
+    /// The span from `start` up to the current position
+    fn span_from(&self, start: Position) -> Span {
+        Span {
+            start,
+            end: self.cur_pos,
+        }
+    }
+
+    /// Moves past the character `c`
+    fn advance(&mut self, c: char) {
         self.cur_pos.column += 1;
+        self.cur_pos.offset += c.len_utf8();
     }
 
     fn newline(&mut self) {
@@ -192,150 +440,348 @@ impl Tokenizer {
         self.cur_pos.row += 1;
     }
 
+    /// Returns whether the last token ends an operand of an expression
+    fn after_operand(&self) -> bool {
+        let mut significant = self.tokens.iter().rev().filter(|tok| !tok.is_trivia());
+        match significant.next() {
+            // words at the start of a statement are mnemonics or directives
+            Some(tok @ &Token::Identifier(_, _)) => {
+                let statement_start = match significant.next() {
+                    Some(prev) => prev.is_newline() || prev.is_colon(),
+                    None => true,
+                };
+                !statement_start && tok.keyword().is_none()
+            }
+            Some(&Token::Number(_, _)) |
+            Some(&Token::Dollar(_)) |
+            Some(&Token::String(_, _)) |
+            Some(&Token::RightParen(_)) => true,
+            _ => false,
+        }
+    }
+
     fn handle_ready(&mut self, c: char) -> Result<(), TokenizerError> {
         match c {
+            ' ' | '\t' | '\r' if self.keep_trivia => {
+                self.state = TokenizerState::ReadingWhitespace(self.cur_pos);
+                self.advance(c);
+                Ok(())
+            }
+
             ' ' | '\t' | '\r' => {
                 self.state = TokenizerState::Ready;
-                self.advance();
+                self.advance(c);
                 Ok(())
             }
 
             '\n' => {
                 self.state = TokenizerState::Ready;
-                self.tokens.push(Token::Newline(self.cur_pos));
-                self.advance();
+                self.tokens.push(Token::Newline(self.char_span(c)));
+                self.advance(c);
                 self.newline();
                 Ok(())
             }
 
-            '0'...'9' => {
+            '0'..='9' | '$' => {
                 self.state = TokenizerState::ReadingNumber(vec![c], self.cur_pos);
-                self.advance();
+                self.advance(c);
                 Ok(())
             }
 
-            '_' | 'a'...'z' | 'A'...'Z' => {
+            // `%` after an operand is the modulo operator, otherwise it starts a binary number
+            '%' if !self.after_operand() => {
+                self.state = TokenizerState::ReadingNumber(vec![c], self.cur_pos);
+                self.advance(c);
+                Ok(())
+            }
+
+            '_' | 'a'..='z' | 'A'..='Z' => {
                 self.state = TokenizerState::ReadingIdentifier(vec![c], self.cur_pos);
-                self.advance();
+                self.advance(c);
                 Ok(())
             }
 
             ':' => {
                 self.state = TokenizerState::Ready;
-                self.tokens.push(Token::Colon(self.cur_pos));
-                self.advance();
+                self.tokens.push(Token::Colon(self.char_span(c)));
+                self.advance(c);
                 Ok(())
             }
 
             ',' => {
                 self.state = TokenizerState::Ready;
-                self.tokens.push(Token::Comma(self.cur_pos));
-                self.advance();
+                self.tokens.push(Token::Comma(self.char_span(c)));
+                self.advance(c);
                 Ok(())
             }
 
             '.' => {
                 self.state = TokenizerState::Ready;
-                self.tokens.push(Token::Dot(self.cur_pos));
-                self.advance();
+                self.tokens.push(Token::Dot(self.char_span(c)));
+                self.advance(c);
                 Ok(())
             }
 
             '@' => {
                 self.state = TokenizerState::Ready;
-                self.tokens.push(Token::At(self.cur_pos));
-                self.advance();
+                self.tokens.push(Token::At(self.char_span(c)));
+                self.advance(c);
                 Ok(())
             }
 
             '#' => {
                 self.state = TokenizerState::Ready;
-                self.tokens.push(Token::Hash(self.cur_pos));
-                self.advance();
+                self.tokens.push(Token::Hash(self.char_span(c)));
+                self.advance(c);
                 Ok(())
             }
 
             '+' => {
                 self.state = TokenizerState::Ready;
-                self.tokens.push(Token::Plus(self.cur_pos));
-                self.advance();
+                self.tokens.push(Token::Plus(self.char_span(c)));
+                self.advance(c);
+                Ok(())
+            }
+
+            '-' => {
+                self.state = TokenizerState::Ready;
+                self.tokens.push(Token::Minus(self.char_span(c)));
+                self.advance(c);
+                Ok(())
+            }
+
+            '*' => {
+                self.state = TokenizerState::Ready;
+                self.tokens.push(Token::Asterisk(self.char_span(c)));
+                self.advance(c);
+                Ok(())
+            }
+
+            '/' => {
+                self.state = TokenizerState::Ready;
+                self.tokens.push(Token::Slash(self.char_span(c)));
+                self.advance(c);
+                Ok(())
+            }
+
+            '(' => {
+                self.state = TokenizerState::Ready;
+                self.tokens.push(Token::LeftParen(self.char_span(c)));
+                self.advance(c);
+                Ok(())
+            }
+
+            ')' => {
+                self.state = TokenizerState::Ready;
+                self.tokens.push(Token::RightParen(self.char_span(c)));
+                self.advance(c);
+                Ok(())
+            }
+
+            '&' => {
+                self.state = TokenizerState::Ready;
+                self.tokens.push(Token::Ampersand(self.char_span(c)));
+                self.advance(c);
+                Ok(())
+            }
+
+            '|' => {
+                self.state = TokenizerState::Ready;
+                self.tokens.push(Token::Pipe(self.char_span(c)));
+                self.advance(c);
+                Ok(())
+            }
+
+            '^' => {
+                self.state = TokenizerState::Ready;
+                self.tokens.push(Token::Caret(self.char_span(c)));
+                self.advance(c);
+                Ok(())
+            }
+
+            '~' => {
+                self.state = TokenizerState::Ready;
+                self.tokens.push(Token::Tilde(self.char_span(c)));
+                self.advance(c);
+                Ok(())
+            }
+
+            '%' => {
+                self.state = TokenizerState::Ready;
+                self.tokens.push(Token::Percent(self.char_span(c)));
+                self.advance(c);
+                Ok(())
+            }
+
+            '=' => {
+                self.state = TokenizerState::Ready;
+                self.tokens.push(Token::Equals(self.char_span(c)));
+                self.advance(c);
+                Ok(())
+            }
+
+            '<' | '>' | '!' => {
+                self.state = TokenizerState::ReadingSymbol(c, self.cur_pos);
+                self.advance(c);
                 Ok(())
             }
 
             ';' => {
-                self.state = TokenizerState::ReadingComment;
-                self.advance();
+                self.state = TokenizerState::ReadingComment(String::new(), self.cur_pos);
+                self.advance(c);
                 Ok(())
             }
 
-            '"' => {
-                self.state = TokenizerState::ReadingString(vec![], self.cur_pos);
-                self.advance();
+            '"' | '\'' => {
+                self.state = TokenizerState::ReadingString(vec![], c, self.cur_pos);
+                self.advance(c);
                 Ok(())
             }
 
             _ => {
                 self.state = TokenizerState::Ready;
-                Err(TokenizerError::UnexpectedCharacter(self.cur_pos, c))
+                let pos = self.cur_pos;
+                self.tokens.push(Token::Error(self.char_span(c)));
+                self.advance(c);
+                Err(TokenizerError::IllegalCharacter(pos, c))
             }
         }
     }
 
-    fn handle_comment(&mut self, c: char) -> Result<(), TokenizerError> {
+    fn handle_comment(&mut self,
+                      mut text: String,
+                      p: Position,
+                      c: char)
+                      -> Result<(), TokenizerError> {
         match c {
             '\n' => {
-                self.tokens.push(Token::Newline(self.cur_pos));
+                if self.keep_trivia {
+                    self.tokens.push(Token::Comment(text, self.span_from(p)));
+                }
+                self.tokens.push(Token::Newline(self.char_span(c)));
                 self.state = TokenizerState::Ready;
-                self.advance();
+                self.advance(c);
                 self.newline();
                 Ok(())
             }
 
             _ => {
-                self.state = TokenizerState::ReadingComment;
-                self.advance();
+                if self.keep_trivia {
+                    text.push(c);
+                }
+                self.state = TokenizerState::ReadingComment(text, p);
+                self.advance(c);
                 Ok(())
             }
         }
     }
 
+    fn handle_whitespace(&mut self, p: Position, c: char) -> Result<(), TokenizerError> {
+        match c {
+            ' ' | '\t' | '\r' => {
+                self.state = TokenizerState::ReadingWhitespace(p);
+                self.advance(c);
+                Ok(())
+            }
+
+            _ => {
+                self.tokens.push(Token::Whitespace(self.span_from(p)));
+                self.state = TokenizerState::Ready;
+                self.consume_char(c)
+            }
+        }
+    }
+
+    /// Returns whether `c` ends a number or a malformed token
+    fn is_delimiter(c: char) -> bool {
+        matches!(c,
+                 ' ' | '\t' | '\r' | '\n' | ',' | '.' | '+' | '-' | '*' | '/' | '(' | ')' | ';' |
+                 '&' | '|' | '^' | '~' | '%' | '=' | '<' | '>' | '!')
+    }
+
+    /// The token of a lone symbol that can start a two-character operator
+    fn single_symbol(first: char, p: Span) -> Token {
+        match first {
+            '<' => Token::Less(p),
+            '>' => Token::Greater(p),
+            '!' => Token::Exclamation(p),
+            _ => unreachable!(),
+        }
+    }
+
+    fn handle_symbol(&mut self, first: char, p: Position, c: char) -> Result<(), TokenizerError> {
+        let token = match (first, c) {
+            ('<', '<') => Token::ShiftLeft,
+            ('>', '>') => Token::ShiftRight,
+            ('<', '=') => Token::LessEqual,
+            ('>', '=') => Token::GreaterEqual,
+            ('<', '>') | ('!', '=') => Token::NotEqual,
+            _ => {
+                self.tokens.push(Self::single_symbol(first, self.span_from(p)));
+                self.state = TokenizerState::Ready;
+                return self.consume_char(c);
+            }
+        };
+        self.advance(c);
+        self.tokens.push(token(self.span_from(p)));
+        self.state = TokenizerState::Ready;
+        Ok(())
+    }
+
+    /// The token of a number read from `p` up to the current position; a `$` without any
+    /// digits after it is the location counter rather than a hex prefix
+    fn number_token(&self, v: Vec<char>, p: Position) -> Token {
+        if v == ['$'] {
+            Token::Dollar(self.span_from(p))
+        } else {
+            Token::Number(v.into_iter().collect(), self.span_from(p))
+        }
+    }
+
     fn handle_number(&mut self,
                      mut v: Vec<char>,
                      p: Position,
                      c: char)
                      -> Result<(), TokenizerError> {
         match c {
-            '0'...'9' | 'a'...'f' | 'A'...'F' | 'h' | 'H' | 'o' | 'O' => {
+            '0'..='9' | 'a'..='f' | 'A'..='F' | 'h' | 'H' | 'o' | 'O' | 'q' | 'Q' | 'x' |
+            'X' | '_' => {
                 v.push(c);
                 self.state = TokenizerState::ReadingNumber(v, p);
-                self.advance();
+                self.advance(c);
                 Ok(())
             }
 
-            ' ' | '\t' | '\r' | '\n' | ',' | '+' | '-' | '*' | '/' | ';' => {
-                self.tokens
-                    .push(Token::Number(v.into_iter().collect(), p));
+            c if Self::is_delimiter(c) => {
+                let token = self.number_token(v, p);
+                self.tokens.push(token);
                 self.state = TokenizerState::Ready;
                 self.consume_char(c)
             }
 
             _ => {
-                self.state = TokenizerState::ReadingNumber(v, p);
-                Err(TokenizerError::UnexpectedCharacter(self.cur_pos, c))
+                // the rest of the number is skipped, so that it isn't read as an identifier
+                self.state = TokenizerState::SkippingInvalid(p);
+                let pos = self.cur_pos;
+                self.advance(c);
+                Err(TokenizerError::MalformedNumber(pos, c))
             }
         }
     }
 
-    fn categorize_identifier(v: Vec<char>, p: Position) -> Token {
-        let s: String = v.into_iter().collect();
-        if let Ok(kw) = s.parse::<Keyword>() {
-            Token::Keyword(kw, p)
-        } else if let Ok(dir) = s.parse::<DirectLocation>() {
-            Token::DirectLocation(dir, p)
-        } else if let Ok(oper) = s.parse::<Operator>() {
-            Token::Operator(oper, p)
-        } else {
-            Token::Identifier(s, p)
+    fn handle_invalid(&mut self, p: Position, c: char) -> Result<(), TokenizerError> {
+        match c {
+            c if Self::is_delimiter(c) => {
+                self.tokens.push(Token::Error(self.span_from(p)));
+                self.state = TokenizerState::Ready;
+                self.consume_char(c)
+            }
+
+            _ => {
+                self.state = TokenizerState::SkippingInvalid(p);
+                self.advance(c);
+                Ok(())
+            }
         }
     }
 
@@ -345,15 +791,16 @@ impl Tokenizer {
                          c: char)
                          -> Result<(), TokenizerError> {
         match c {
-            'a'...'z' | 'A'...'Z' | '0'...'9' | '_' => {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' => {
                 v.push(c);
                 self.state = TokenizerState::ReadingIdentifier(v, p);
-                self.advance();
+                self.advance(c);
                 Ok(())
             }
 
             _ => {
-                self.tokens.push(Self::categorize_identifier(v, p));
+                self.tokens
+                    .push(Token::Identifier(v.into_iter().collect(), self.span_from(p)));
                 self.state = TokenizerState::Ready;
                 self.consume_char(c)
             }
@@ -361,63 +808,162 @@ impl Tokenizer {
     }
 
     fn handle_string(&mut self,
-                     mut v: Vec<char>,
+                     mut v: Vec<u8>,
+                     quote: char,
                      p: Position,
                      c: char)
                      -> Result<(), TokenizerError> {
         match c {
-            '"' => {
-                self.tokens
-                    .push(Token::String(v.into_iter().collect(), p));
-                self.state = TokenizerState::Ready;
-                self.advance();
+            c if c == quote => {
+                self.state = TokenizerState::ReadingStringQuote(v, quote, p);
+                self.advance(c);
                 Ok(())
             }
             '\\' => {
-                self.state = TokenizerState::ReadingStringEscape(v, p);
-                self.advance();
+                self.state = TokenizerState::ReadingStringEscape(v, quote, p);
+                self.advance(c);
                 Ok(())
             }
-            '\n' => Err(TokenizerError::UnexpectedCharacter(self.cur_pos, '\n')),
+            '\n' => {
+                // the string ends with the line, and the line break is read as usual
+                self.tokens.push(Token::Error(self.span_from(p)));
+                self.bad_escape = false;
+                self.state = TokenizerState::Ready;
+                self.consume_char(c)?;
+                Err(TokenizerError::UnterminatedString(p))
+            }
             c => {
-                v.push(c);
-                self.state = TokenizerState::ReadingString(v, p);
-                self.advance();
+                let mut buf = [0; 4];
+                v.extend_from_slice(c.encode_utf8(&mut buf).as_bytes());
+                self.state = TokenizerState::ReadingString(v, quote, p);
+                self.advance(c);
                 Ok(())
             }
         }
     }
 
+    fn handle_string_quote(&mut self,
+                           mut v: Vec<u8>,
+                           quote: char,
+                           p: Position,
+                           c: char)
+                           -> Result<(), TokenizerError> {
+        if c == quote {
+            v.push(quote as u8);
+            self.state = TokenizerState::ReadingString(v, quote, p);
+            self.advance(c);
+            Ok(())
+        } else {
+            self.end_string(v, p);
+            self.state = TokenizerState::Ready;
+            self.consume_char(c)
+        }
+    }
+
+    /// Emits the string that has just been closed, or an error token in its place if it has an
+    /// invalid escape in it
+    fn end_string(&mut self, v: Vec<u8>, p: Position) {
+        let span = self.span_from(p);
+        if mem::replace(&mut self.bad_escape, false) {
+            self.tokens.push(Token::Error(span));
+        } else {
+            self.tokens.push(Token::String(v, span));
+        }
+    }
+
     fn handle_string_escape(&mut self,
-                            mut v: Vec<char>,
+                            mut v: Vec<u8>,
+                            quote: char,
                             p: Position,
                             c: char)
                             -> Result<(), TokenizerError> {
         match c {
             'r' => {
-                v.push('\r');
+                v.push(b'\r');
             }
             'n' => {
-                v.push('\n');
+                v.push(b'\n');
             }
             't' => {
-                v.push('\t');
+                v.push(b'\t');
+            }
+            '0' => {
+                v.push(0);
+            }
+            'a' => {
+                v.push(0x07);
+            }
+            'e' => {
+                v.push(0x1B);
             }
             '\\' => {
-                v.push('\\');
+                v.push(b'\\');
             }
             '"' => {
-                v.push('"');
+                v.push(b'"');
+            }
+            '\'' => {
+                v.push(b'\'');
+            }
+            'x' => {
+                self.advance(c);
+                self.state = TokenizerState::ReadingStringHex(v, quote, p, None);
+                return Ok(());
+            }
+            '\n' => {
+                return self.handle_string(v, quote, p, c);
             }
             c => {
-                return Err(TokenizerError::UnexpectedCharacter(self.cur_pos, c));
+                // the rest of the string is read as usual, so that it ends where it should
+                let pos = self.cur_pos;
+                self.bad_escape = true;
+                self.advance(c);
+                self.state = TokenizerState::ReadingString(v, quote, p);
+                return Err(TokenizerError::InvalidEscape(pos, c));
             }
         }
-        self.advance();
-        self.state = TokenizerState::ReadingString(v, p);
+        self.advance(c);
+        self.state = TokenizerState::ReadingString(v, quote, p);
         Ok(())
     }
 
+    fn handle_string_hex(&mut self,
+                         mut v: Vec<u8>,
+                         quote: char,
+                         p: Position,
+                         high: Option<u8>,
+                         c: char)
+                         -> Result<(), TokenizerError> {
+        match (c.to_digit(16), high) {
+            (Some(digit), None) => {
+                self.state = TokenizerState::ReadingStringHex(v, quote, p, Some(digit as u8));
+                self.advance(c);
+                Ok(())
+            }
+            (Some(digit), Some(high)) => {
+                v.push(high * 16 + digit as u8);
+                self.state = TokenizerState::ReadingString(v, quote, p);
+                self.advance(c);
+                Ok(())
+            }
+            (None, _) if c == '\n' => {
+                // like a backslash at the end of the line, the escape is cut short by the line
+                // break, and it's the string that is left unterminated
+                self.handle_string(v, quote, p, c)
+            }
+            (None, _) => {
+                // the character is read as a part of the string, which can't fail, since it
+                // can't be a line break
+                let pos = self.cur_pos;
+                self.bad_escape = true;
+                self.state = TokenizerState::ReadingString(v, quote, p);
+                self.consume_char(c).and(Err(TokenizerError::InvalidEscape(pos, c)))
+            }
+        }
+    }
+
+    /// Consumes a single character of the source. After an error the invalid part of the source
+    /// is turned into a `Token::Error` and the tokenizer is ready to carry on.
     pub fn consume_char(&mut self, c: char) -> Result<(), TokenizerError> {
         match mem::replace(&mut self.state, TokenizerState::Invalid) {
             TokenizerState::Ready => self.handle_ready(c),
@@ -426,28 +972,155 @@ impl Tokenizer {
 
             TokenizerState::ReadingIdentifier(v, p) => self.handle_identifier(v, p, c),
 
-            TokenizerState::ReadingString(v, p) => self.handle_string(v, p, c),
+            TokenizerState::ReadingString(v, q, p) => self.handle_string(v, q, p, c),
+
+            TokenizerState::ReadingStringEscape(v, q, p) => self.handle_string_escape(v, q, p, c),
+
+            TokenizerState::ReadingStringHex(v, q, p, high) => {
+                self.handle_string_hex(v, q, p, high, c)
+            }
+
+            TokenizerState::ReadingStringQuote(v, q, p) => self.handle_string_quote(v, q, p, c),
+
+            TokenizerState::ReadingComment(text, p) => self.handle_comment(text, p, c),
 
-            TokenizerState::ReadingStringEscape(v, p) => self.handle_string_escape(v, p, c),
+            TokenizerState::ReadingWhitespace(p) => self.handle_whitespace(p, c),
 
-            TokenizerState::ReadingComment => self.handle_comment(c),
+            TokenizerState::ReadingSymbol(first, p) => self.handle_symbol(first, p, c),
+
+            TokenizerState::SkippingInvalid(p) => self.handle_invalid(p, c),
 
             TokenizerState::Invalid => panic!("Tokenizer caught in invalid state"),
         }
     }
 
-    pub fn consume_text(&mut self, text: &str) -> Result<(), TokenizerError> {
-        for c in text.chars() {
-            self.consume_char(c)?;
+    /// Consumes the whole text, returning all the errors found in it
+    pub fn consume_text(&mut self, text: &str) -> Result<(), Vec<TokenizerError>> {
+        let errors: Vec<_> = text.chars().filter_map(|c| self.consume_char(c).err()).collect();
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Emits the token that was being read when the text ended
+    fn finish(&mut self) -> Result<(), TokenizerError> {
+        match mem::replace(&mut self.state, TokenizerState::Invalid) {
+            TokenizerState::Ready => {
+                self.state = TokenizerState::Ready;
+            }
+
+            TokenizerState::ReadingNumber(v, p) => {
+                let token = self.number_token(v, p);
+                self.tokens.push(token);
+                self.state = TokenizerState::Ready;
+            }
+
+            TokenizerState::ReadingIdentifier(v, p) => {
+                self.tokens
+                    .push(Token::Identifier(v.into_iter().collect(), self.span_from(p)));
+                self.state = TokenizerState::Ready;
+            }
+
+            TokenizerState::ReadingStringQuote(v, _, p) => {
+                self.end_string(v, p);
+                self.state = TokenizerState::Ready;
+            }
+
+            TokenizerState::ReadingString(_, _, p) |
+            TokenizerState::ReadingStringEscape(_, _, p) |
+            TokenizerState::ReadingStringHex(_, _, p, _) => {
+                self.tokens.push(Token::Error(self.span_from(p)));
+                self.bad_escape = false;
+                self.state = TokenizerState::Ready;
+                return Err(TokenizerError::UnterminatedString(p));
+            }
+
+            TokenizerState::ReadingComment(text, p) => {
+                if self.keep_trivia {
+                    self.tokens.push(Token::Comment(text, self.span_from(p)));
+                }
+                self.state = TokenizerState::Ready;
+            }
+
+            TokenizerState::ReadingWhitespace(p) => {
+                self.tokens.push(Token::Whitespace(self.span_from(p)));
+                self.state = TokenizerState::Ready;
+            }
+
+            TokenizerState::ReadingSymbol(first, p) => {
+                self.tokens.push(Self::single_symbol(first, self.span_from(p)));
+                self.state = TokenizerState::Ready;
+            }
+
+            TokenizerState::SkippingInvalid(p) => {
+                self.tokens.push(Token::Error(self.span_from(p)));
+                self.state = TokenizerState::Ready;
+            }
+
+            TokenizerState::Invalid => panic!("Tokenizer caught in invalid state"),
         }
         Ok(())
     }
 
-    pub fn tokenize(text: &str) -> Result<Vec<Token>, TokenizerError> {
-        let mut tokenizer = Tokenizer::new();
-        tokenizer.consume_text(text)?;
-        tokenizer.flush().expect("Tokenizer.flush() failed");
-        Ok(tokenizer.get_tokens())
+    /// Tokenizes the text, failing with all the errors found in it
+    pub fn tokenize(text: &str) -> Result<Vec<Token>, Vec<TokenizerError>> {
+        let (tokens, errors) = Tokenizer::tokenize_lossy(text);
+        if errors.is_empty() {
+            Ok(tokens)
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Tokenizes the text, putting `Token::Error` in place of the parts that couldn't be
+    /// tokenized, so that the rest of the text can still be parsed
+    pub fn tokenize_lossy(text: &str) -> (Vec<Token>, Vec<TokenizerError>) {
+        Tokenizer::new().tokenize_all(text)
+    }
+
+    /// Tokenizes the text like `tokenize_lossy`, but also keeps the whitespace and the comments,
+    /// along with the spelling of every token, so that the text can be rebuilt from the tokens
+    pub fn tokenize_lossless(text: &str) -> (Vec<SourceToken>, Vec<TokenizerError>) {
+        let (tokens, errors) = Tokenizer::with_trivia().tokenize_all(text);
+        let tokens = tokens.into_iter()
+            .map(|token| {
+                let span = token.get_span();
+                SourceToken {
+                    text: text[span.start().offset()..span.end().offset()].to_owned(),
+                    token,
+                }
+            })
+            .collect();
+        (tokens, errors)
+    }
+
+    /// Turns the tokenizer into an iterator over the tokens of `source`, which is read one line
+    /// at a time. The errors found in a line are yielded after its tokens, and the line is still
+    /// tokenized with `Token::Error` in place of the invalid parts, like in `tokenize_lossy`.
+    pub fn stream<R: Read>(self, source: R) -> TokenStream<BufReader<R>> {
+        TokenStream {
+            source: BufReader::new(source),
+            tokenizer: self,
+            pending: VecDeque::new(),
+            line: String::new(),
+            finished: false,
+        }
+    }
+
+    fn tokenize_all(mut self, text: &str) -> (Vec<Token>, Vec<TokenizerError>) {
+        let mut errors = self.consume_text(text).err().unwrap_or_default();
+        if let Err(e) = self.finish() {
+            errors.push(e);
+        }
+        (self.get_tokens(), errors)
+    }
+}
+
+impl Default for Tokenizer {
+    fn default() -> Tokenizer {
+        Tokenizer::new()
     }
 }
 
@@ -459,43 +1132,62 @@ impl Write for Tokenizer {
         }
         let text = chars.unwrap();
         let result = self.consume_text(text);
-        if let Err(e) = result {
-            return Err(io::Error::new(io::ErrorKind::InvalidInput, e));
+        if let Err(mut errors) = result {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, errors.remove(0)));
         }
         Ok(buf.len())
     }
 
     fn flush(&mut self) -> io::Result<()> {
-        match mem::replace(&mut self.state, TokenizerState::Invalid) {
-            TokenizerState::Ready => {
-                self.state = TokenizerState::Ready;
-            }
+        self.finish().map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+    }
+}
 
-            TokenizerState::ReadingNumber(v, p) => {
-                self.tokens
-                    .push(Token::Number(v.into_iter().collect(), p));
-                self.state = TokenizerState::Ready;
-            }
+/// An iterator over the tokens of a source, created by `Tokenizer::stream`
+pub struct TokenStream<R> {
+    source: R,
+    tokenizer: Tokenizer,
+    /// The tokens and errors of the lines read so far that haven't been yielded yet
+    pending: VecDeque<Result<Token, TokenizerError>>,
+    line: String,
+    finished: bool,
+}
 
-            TokenizerState::ReadingIdentifier(v, p) => {
-                self.tokens.push(Self::categorize_identifier(v, p));
-                self.state = TokenizerState::Ready;
+impl<R: BufRead> TokenStream<R> {
+    /// Reads and tokenizes the next line of the source
+    fn read_line(&mut self) {
+        self.line.clear();
+        let mut errors = Vec::new();
+        match self.source.read_line(&mut self.line) {
+            Ok(0) => {
+                errors.extend(self.tokenizer.finish().err());
+                self.finished = true;
             }
-
-            TokenizerState::ReadingString(v, p) |
-            TokenizerState::ReadingStringEscape(v, p) => {
-                self.tokens
-                    .push(Token::String(v.into_iter().collect(), p));
-                self.state = TokenizerState::Ready;
+            Ok(_) => {
+                for c in self.line.chars() {
+                    errors.extend(self.tokenizer.consume_char(c).err());
+                }
             }
-
-            TokenizerState::ReadingComment => {
-                self.state = TokenizerState::Ready;
+            Err(e) => {
+                errors.push(TokenizerError::Io(self.tokenizer.cur_pos, e.kind()));
+                self.finished = true;
             }
+        }
 
-            TokenizerState::Invalid => panic!("Tokenizer caught in invalid state"),
+        // only whole lines are taken out, so that `%` still sees the tokens before it
+        self.pending.extend(self.tokenizer.tokens.drain(..).map(Ok));
+        self.pending.extend(errors.into_iter().map(Err));
+    }
+}
+
+impl<R: BufRead> Iterator for TokenStream<R> {
+    type Item = Result<Token, TokenizerError>;
+
+    fn next(&mut self) -> Option<Result<Token, TokenizerError>> {
+        while self.pending.is_empty() && !self.finished {
+            self.read_line();
         }
-        Ok(())
+        self.pending.pop_front()
     }
 }
 
@@ -510,53 +1202,55 @@ mod tests {
             assert_eq!(result.len(), 6);
 
             // label
-            if let Token::Identifier(ref s, pos) = result[0] {
+            if let Token::Identifier(ref s, span) = result[0] {
                 assert_eq!(s, "label");
-                assert_eq!(pos.row, 1);
-                assert_eq!(pos.column, 1);
+                assert_eq!(span.start.row, 1);
+                assert_eq!(span.start.column, 1);
+                assert_eq!(span.end.column, 6);
             } else {
                 panic!("result[0]: expected Identifier, found {:?}", result[0]);
             }
 
             // :
-            if let Token::Colon(pos) = result[1] {
-                assert_eq!(pos.row, 1);
-                assert_eq!(pos.column, 6);
+            if let Token::Colon(span) = result[1] {
+                assert_eq!(span.start.row, 1);
+                assert_eq!(span.start.column, 6);
             } else {
                 panic!("result[1]: expected Colon, found {:?}", result[1]);
             }
 
             // operator
-            if let Token::Identifier(ref s, pos) = result[2] {
+            if let Token::Identifier(ref s, span) = result[2] {
                 assert_eq!(s, "operator");
-                assert_eq!(pos.row, 1);
-                assert_eq!(pos.column, 8);
+                assert_eq!(span.start.row, 1);
+                assert_eq!(span.start.column, 8);
             } else {
                 panic!("result[2]: expected Identifier, found {:?}", result[2]);
             }
 
             // operand1
-            if let Token::Identifier(ref s, pos) = result[3] {
+            if let Token::Identifier(ref s, span) = result[3] {
                 assert_eq!(s, "operand1");
-                assert_eq!(pos.row, 1);
-                assert_eq!(pos.column, 17);
+                assert_eq!(span.start.row, 1);
+                assert_eq!(span.start.column, 17);
             } else {
                 panic!("result[3]: expected Identifier, found {:?}", result[3]);
             }
 
             // ,
-            if let Token::Comma(pos) = result[4] {
-                assert_eq!(pos.row, 1);
-                assert_eq!(pos.column, 25);
+            if let Token::Comma(span) = result[4] {
+                assert_eq!(span.start.row, 1);
+                assert_eq!(span.start.column, 25);
             } else {
                 panic!("result[4]: expected Comma, found {:?}", result[4]);
             }
 
             // 0EFh
-            if let Token::Number(ref s, pos) = result[5] {
+            if let Token::Number(ref s, span) = result[5] {
                 assert_eq!(s, "0EFh");
-                assert_eq!(pos.row, 1);
-                assert_eq!(pos.column, 27);
+                assert_eq!(span.start.row, 1);
+                assert_eq!(span.start.column, 27);
+                assert_eq!(span.end.column, 31);
             } else {
                 panic!("result[5]: expected Number, found {:?}", result[5]);
             }
@@ -572,78 +1266,210 @@ mod tests {
             assert_eq!(result.len(), 7);
 
             // mov
-            if let Token::Operator(oper, pos) = result[0] {
-                assert_eq!(oper, Operator::Mov);
-                assert_eq!(pos.row, 1);
-                assert_eq!(pos.column, 1);
+            if let Token::Identifier(ref s, span) = result[0] {
+                assert_eq!(s, "mov");
+                assert_eq!(result[0].operator(), Some(Operator::Mov));
+                assert_eq!(span.start.row, 1);
+                assert_eq!(span.start.column, 1);
             } else {
-                panic!("result[0]: expected Operator, found {:?}", result[0]);
+                panic!("result[0]: expected Identifier, found {:?}", result[0]);
             }
 
             // a
-            if let Token::Identifier(ref s, pos) = result[1] {
+            if let Token::Identifier(ref s, span) = result[1] {
                 assert_eq!(s, "a");
-                assert_eq!(pos.row, 1);
-                assert_eq!(pos.column, 5);
+                assert_eq!(span.start.row, 1);
+                assert_eq!(span.start.column, 5);
             } else {
                 panic!("result[1]: expected Identifier, found {:?}", result[1]);
             }
 
             // ,
-            if let Token::Comma(pos) = result[2] {
-                assert_eq!(pos.row, 1);
-                assert_eq!(pos.column, 6);
+            if let Token::Comma(span) = result[2] {
+                assert_eq!(span.start.row, 1);
+                assert_eq!(span.start.column, 6);
             } else {
                 panic!("result[2]: expected Comma, found {:?}", result[2]);
             }
 
             // #
-            if let Token::Hash(pos) = result[3] {
-                assert_eq!(pos.row, 1);
-                assert_eq!(pos.column, 8);
+            if let Token::Hash(span) = result[3] {
+                assert_eq!(span.start.row, 1);
+                assert_eq!(span.start.column, 8);
             } else {
                 panic!("result[3]: expected Hash, found {:?}", result[3]);
             }
 
             // 20h
-            if let Token::Number(ref s, pos) = result[4] {
+            if let Token::Number(ref s, span) = result[4] {
                 assert_eq!(s, "20h");
-                assert_eq!(pos.row, 1);
-                assert_eq!(pos.column, 9);
+                assert_eq!(span.start.row, 1);
+                assert_eq!(span.start.column, 9);
             } else {
                 panic!("result[4]: expected Number, found {:?}", result[4]);
             }
 
             // \n
-            if let Token::Newline(pos) = result[5] {
-                assert_eq!(pos.row, 1);
-                assert_eq!(pos.column, 12);
+            if let Token::Newline(span) = result[5] {
+                assert_eq!(span.start.row, 1);
+                assert_eq!(span.start.column, 12);
             } else {
                 panic!("result[5]: expected Newline, found {:?}", result[5]);
             }
 
             // ret
-            if let Token::Operator(oper, pos) = result[6] {
-                assert_eq!(oper, Operator::Ret);
-                assert_eq!(pos.row, 2);
-                assert_eq!(pos.column, 1);
+            if let Token::Identifier(_, span) = result[6] {
+                assert_eq!(result[6].operator(), Some(Operator::Ret));
+                assert_eq!(span.start.row, 2);
+                assert_eq!(span.start.column, 1);
             } else {
-                panic!("result[6]: expected Operator, found {:?}", result[6]);
+                panic!("result[6]: expected Identifier, found {:?}", result[6]);
             }
         } else {
             panic!("Tokenization failed!");
         }
     }
 
+    #[test]
+    fn test_operators() {
+        let text = "#-1 (a<<2)>>x & c|d ^ ~e % f < g > h = i ! <= >= <> != <";
+        let result = Tokenizer::tokenize(text).unwrap();
+        let kinds: Vec<_> = result.iter()
+            .filter(|tok| !tok.is_identifier() && !tok.is_number())
+            .map(mem::discriminant)
+            .collect();
+        let span = result[0].get_span();
+        let expected: Vec<_> = vec![Token::Hash(span),
+                                    Token::Minus(span),
+                                    Token::LeftParen(span),
+                                    Token::ShiftLeft(span),
+                                    Token::RightParen(span),
+                                    Token::ShiftRight(span),
+                                    Token::Ampersand(span),
+                                    Token::Pipe(span),
+                                    Token::Caret(span),
+                                    Token::Tilde(span),
+                                    Token::Percent(span),
+                                    Token::Less(span),
+                                    Token::Greater(span),
+                                    Token::Equals(span),
+                                    Token::Exclamation(span),
+                                    Token::LessEqual(span),
+                                    Token::GreaterEqual(span),
+                                    Token::NotEqual(span),
+                                    Token::NotEqual(span),
+                                    Token::Less(span)]
+            .iter()
+            .map(mem::discriminant)
+            .collect();
+        assert_eq!(kinds, expected);
+
+        // two-character operators span both characters, numbers end before them
+        let shift_span = result[5].get_span();
+        assert_eq!((shift_span.start().column(), shift_span.end().column()), (7, 9));
+        assert_eq!(result[6].get_string(), Some("2".to_owned()));
+        let last_span = result.last().unwrap().get_span();
+        assert_eq!(last_span.len(), 1);
+    }
+
+    #[test]
+    fn test_number_formats() {
+        let text = "0x1F, $1f, %1010, 0b1010, 17q, 99d, 1_000, 0FF_FFh";
+        let result = Tokenizer::tokenize(text).unwrap();
+        let numbers: Vec<_> = result.iter().filter_map(|tok| tok.get_string()).collect();
+        assert_eq!(numbers,
+                   vec!["0x1F", "$1f", "%1010", "0b1010", "17q", "99d", "1_000", "0FF_FFh"]);
+    }
+
+    #[test]
+    fn test_dollar() {
+        let text = "sjmp $\nlen equ $-msg, $1f";
+        let result = Tokenizer::tokenize(text).unwrap();
+        assert_eq!(result.len(), 10);
+        if let Token::Dollar(span) = result[1] {
+            assert_eq!(span.start().column(), 6);
+            assert_eq!(span.len(), 1);
+        } else {
+            panic!("result[1]: expected Dollar, found {:?}", result[1]);
+        }
+        assert!(matches!(result[5], Token::Dollar(_)));
+        assert_eq!(result[9].get_string(), Some("$1f".to_owned()));
+    }
+
+    #[test]
+    fn test_percent_after_operand() {
+        let text = "db a %10 (1)%2 3 % 4 + %11";
+        let result = Tokenizer::tokenize(text).unwrap();
+        assert_eq!(result.len(), 14);
+        assert!(matches!(result[2], Token::Percent(_)));
+        assert!(matches!(result[7], Token::Percent(_)));
+        assert!(matches!(result[10], Token::Percent(_)));
+        assert_eq!(result[13].get_string(), Some("%11".to_owned()));
+    }
+
+    #[test]
+    fn test_percent_after_mnemonic() {
+        let text = "db %1\nlabel: db %10\nx equ %11 mod %100";
+        let result = Tokenizer::tokenize(text).unwrap();
+        let numbers: Vec<_> = result.iter()
+            .filter(|tok| tok.is_number())
+            .map(|tok| tok.get_string().unwrap())
+            .collect();
+        assert_eq!(numbers, vec!["%1", "%10", "%11", "%100"]);
+    }
+
+    #[test]
+    fn test_lossless() {
+        let text = "start:\tMOV  A, #'x' ; load\r\n  db \"a\\x41\", 0FFh;\n\n;end";
+        let (tokens, errors) = Tokenizer::tokenize_lossless(text);
+        assert!(errors.is_empty());
+        let rebuilt: String = tokens.iter().map(|tok| &tok.text[..]).collect();
+        assert_eq!(rebuilt, text);
+
+        assert_eq!(tokens[2].token, Token::Whitespace(tokens[2].token.get_span()));
+        assert_eq!(tokens[2].text, "\t");
+        assert_eq!(tokens[3].text, "MOV");
+        assert_eq!(tokens[9].text, "'x'");
+        let comments: Vec<_> = tokens.iter()
+            .filter_map(|tok| match tok.token {
+                            Token::Comment(ref s, _) => Some(&s[..]),
+                            _ => None,
+                        })
+            .collect();
+        assert_eq!(comments, vec![" load\r", "", "end"]);
+    }
+
+    #[test]
+    fn test_no_trivia_by_default() {
+        let text = "  nop ; comment\n";
+        let result = Tokenizer::tokenize(text).unwrap();
+        assert_eq!(result.len(), 2);
+        assert!(!result.iter().any(Token::is_trivia));
+    }
+
+    #[test]
+    fn test_lossless_matches_default() {
+        let text = "mov a, #10 % 3\n  db %1010, #7 %2 ; mask\nlbl: mov a, $ % 2\n  inc %1";
+        let (lossless, lossless_errors) = Tokenizer::tokenize_lossless(text);
+        let (tokens, errors) = Tokenizer::tokenize_lossy(text);
+        let significant: Vec<_> = lossless.into_iter()
+            .map(|tok| tok.token)
+            .filter(|tok| !tok.is_trivia())
+            .collect();
+        assert_eq!(significant, tokens);
+        assert_eq!(lossless_errors, errors);
+    }
+
     #[test]
     fn test_string() {
         let text = "\"abcdefg\"";
         if let Ok(result) = Tokenizer::tokenize(text) {
             assert_eq!(result.len(), 1);
-            if let Token::String(ref s, pos) = result[0] {
-                assert_eq!(s, "abcdefg");
-                assert_eq!(pos.row, 1);
-                assert_eq!(pos.column, 1);
+            if let Token::String(ref s, span) = result[0] {
+                assert_eq!(s, b"abcdefg");
+                assert_eq!(span.start.row, 1);
+                assert_eq!(span.start.column, 1);
+                assert_eq!(span.end.column, 10);
             } else {
                 panic!("result[0]: expected String, found {:?}", result[0]);
             }
@@ -652,15 +1478,37 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_byte_offsets() {
+        let text = "db \"zażółć\", 1\n  ret";
+        let result = Tokenizer::tokenize(text).unwrap();
+        assert_eq!(result.len(), 6);
+
+        let string_span = result[1].get_span();
+        assert_eq!(string_span.start().offset(), 3);
+        assert_eq!(string_span.end().offset(), 15);
+        assert_eq!(string_span.end().column(), 12);
+        assert_eq!(string_span.len(), 12);
+
+        let number_span = result[3].get_span();
+        assert_eq!(number_span.start().offset(), 17);
+        assert_eq!(number_span.len(), 1);
+
+        let ret_span = result[5].get_span();
+        assert_eq!((ret_span.start().row(), ret_span.start().column()), (2, 3));
+        assert_eq!(ret_span.start().offset(), 21);
+        assert_eq!(&text[ret_span.start().offset()..ret_span.end().offset()], "ret");
+    }
+
     #[test]
     fn test_string_escape() {
         let text = "\"abcd\\nefg\"";
         if let Ok(result) = Tokenizer::tokenize(text) {
             assert_eq!(result.len(), 1);
-            if let Token::String(ref s, pos) = result[0] {
-                assert_eq!(s, "abcd\nefg");
-                assert_eq!(pos.row, 1);
-                assert_eq!(pos.column, 1);
+            if let Token::String(ref s, span) = result[0] {
+                assert_eq!(s, b"abcd\nefg");
+                assert_eq!(span.start.row, 1);
+                assert_eq!(span.start.column, 1);
             } else {
                 panic!("result[0]: expected String, found {:?}", result[0]);
             }
@@ -674,10 +1522,10 @@ mod tests {
         let text = "\"abcd\\\"efg\"";
         if let Ok(result) = Tokenizer::tokenize(text) {
             assert_eq!(result.len(), 1);
-            if let Token::String(ref s, pos) = result[0] {
-                assert_eq!(s, "abcd\"efg");
-                assert_eq!(pos.row, 1);
-                assert_eq!(pos.column, 1);
+            if let Token::String(ref s, span) = result[0] {
+                assert_eq!(s, b"abcd\"efg");
+                assert_eq!(span.start.row, 1);
+                assert_eq!(span.start.column, 1);
             } else {
                 panic!("result[0]: expected String, found {:?}", result[0]);
             }
@@ -686,28 +1534,187 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_string_more_escapes() {
+        let text = r#""\0\a\e\x41\xfF\'ż""#;
+        let result = Tokenizer::tokenize(text).unwrap();
+        assert_eq!(result.len(), 1);
+        if let Token::String(ref s, _) = result[0] {
+            assert_eq!(s, &[0x00, 0x07, 0x1B, 0x41, 0xFF, b'\'', 0xC5, 0xBC]);
+        } else {
+            panic!("result[0]: expected String, found {:?}", result[0]);
+        }
+    }
+
+    #[test]
+    fn test_string_invalid_hex_escape() {
+        let (tokens, errors) = Tokenizer::tokenize_lossy(r#""a\x4g""#);
+        assert_eq!(errors,
+                   vec![TokenizerError::InvalidEscape(Position {
+                                                          row: 1,
+                                                          column: 6,
+                                                          offset: 5,
+                                                      },
+                                                      'g')]);
+        assert_eq!(tokens.len(), 1);
+        assert!(tokens[0].is_error());
+        assert_eq!(tokens[0].get_span().end().column(), 8);
+
+        // an escape cut short by the end of the line leaves the string unterminated
+        let (tokens, errors) = Tokenizer::tokenize_lossy("\"\\x4\nnop");
+        assert_eq!(errors,
+                   vec![TokenizerError::UnterminatedString(Position {
+                                                               row: 1,
+                                                               column: 1,
+                                                               offset: 0,
+                                                           })]);
+        assert!(tokens[0].is_error());
+        assert!(tokens[1].is_newline());
+
+        // the character after the escape is read as usual, even if it starts another escape
+        let (tokens, errors) = Tokenizer::tokenize_lossy(r#""\x\q""#);
+        assert_eq!(errors.len(), 2);
+        assert_eq!(tokens.len(), 1);
+        assert!(tokens[0].is_error());
+    }
+
+    #[test]
+    fn test_single_quoted() {
+        let text = "'It''s', 'A', \"say \"\"hi\"\"\", ''";
+        let result = Tokenizer::tokenize(text).unwrap();
+        let strings: Vec<_> = result.iter()
+            .filter_map(|tok| match *tok {
+                            Token::String(ref s, _) => Some(s.clone()),
+                            _ => None,
+                        })
+            .collect();
+        assert_eq!(strings,
+                   vec![b"It's".to_vec(), b"A".to_vec(), b"say \"hi\"".to_vec(), vec![]]);
+        assert_eq!(result[0].get_span().end().column(), 8);
+        assert!(result[1].is_comma());
+    }
+
     #[test]
     fn test_string_invalid_escape() {
         let text = "\"abcd\\yefg\"";
-        if let Err(TokenizerError::UnexpectedCharacter(pos, c)) = Tokenizer::tokenize(text) {
+        let (tokens, errors) = Tokenizer::tokenize_lossy(text);
+        assert_eq!(errors.len(), 1);
+        if let TokenizerError::InvalidEscape(pos, c) = errors[0] {
             assert_eq!(pos.row, 1);
             assert_eq!(pos.column, 7);
             assert_eq!(c, 'y');
         } else {
-            panic!("Tokenization successful!");
+            panic!("Expected an invalid escape error, found {:?}", errors[0]);
         }
+        // the string is still read up to its end, but becomes an error token
+        assert_eq!(tokens.len(), 1);
+        assert!(tokens[0].is_error());
+        assert_eq!(tokens[0].get_span().end().column(), 12);
     }
 
     #[test]
     fn test_invalid_number() {
         let text = "0abcdefgh";
-        if let Err(TokenizerError::UnexpectedCharacter(pos, c)) = Tokenizer::tokenize(text) {
-            assert_eq!(pos.row, 1);
-            assert_eq!(pos.column, 8);
-            assert_eq!(c, 'g');
+        if let Err(errors) = Tokenizer::tokenize(text) {
+            assert_eq!(errors.len(), 1);
+            if let TokenizerError::MalformedNumber(pos, c) = errors[0] {
+                assert_eq!(pos.row, 1);
+                assert_eq!(pos.column, 8);
+                assert_eq!(c, 'g');
+            } else {
+                panic!("Expected a malformed number error, found {:?}", errors[0]);
+            }
         } else {
             panic!("Tokenization successful!");
         }
     }
 
+    #[test]
+    fn test_unterminated_string() {
+        let text = "db \"abc\nret";
+        let (tokens, errors) = Tokenizer::tokenize_lossy(text);
+        assert_eq!(errors.len(), 1);
+        if let TokenizerError::UnterminatedString(pos) = errors[0] {
+            assert_eq!(pos.row, 1);
+            assert_eq!(pos.column, 4);
+        } else {
+            panic!("Expected an unterminated string error, found {:?}", errors[0]);
+        }
+        assert_eq!(tokens.len(), 4);
+        if let Token::Error(span) = tokens[1] {
+            assert_eq!(span.start.column, 4);
+            assert_eq!(span.end.column, 8);
+        } else {
+            panic!("tokens[1]: expected Error, found {:?}", tokens[1]);
+        }
+        assert!(tokens[2].is_newline());
+        assert_eq!(tokens[3].operator(), Some(Operator::Ret));
+    }
+
+    #[test]
+    fn test_error_recovery() {
+        let text = "mov a, ?12\nmov 12z3h, a\ndb \"a\\q\", \"b";
+        let (tokens, errors) = Tokenizer::tokenize_lossy(text);
+        assert_eq!(errors,
+                   vec![TokenizerError::IllegalCharacter(Position { row: 1, column: 8, offset: 7 }, '?'),
+                        TokenizerError::MalformedNumber(Position { row: 2, column: 7, offset: 17 }, 'z'),
+                        TokenizerError::InvalidEscape(Position { row: 3, column: 7, offset: 30 }, 'q'),
+                        TokenizerError::UnterminatedString(Position { row: 3, column: 11, offset: 34 })]);
+
+        // the number after the illegal character is still read
+        assert!(tokens[3].is_error());
+        assert!(tokens[4].is_number());
+        // the malformed number is a single token, followed by the comma
+        assert!(tokens[7].is_error());
+        assert_eq!(tokens[7].get_span().end().column(), 10);
+        assert!(tokens[8].is_comma());
+        assert!(tokens.last().unwrap().is_error());
+    }
+
+    #[test]
+    fn test_stream() {
+        let text = "start: mov a, #10 % 3\n  db %1010, 'ab' ; comment\r\n\nsjmp start";
+        let (tokens, _) = Tokenizer::tokenize_lossy(text);
+        let streamed: Result<Vec<_>, _> = Tokenizer::new().stream(text.as_bytes()).collect();
+        assert_eq!(streamed, Ok(tokens));
+    }
+
+    #[test]
+    fn test_stream_errors() {
+        let text = "mov a, ?12\nmov 12z3h, a\ndb \"a\\q\", \"b";
+        let (tokens, errors) = Tokenizer::tokenize_lossy(text);
+        let (streamed, streamed_errors): (Vec<_>, Vec<_>) =
+            Tokenizer::new().stream(text.as_bytes()).partition(|item| item.is_ok());
+        assert_eq!(streamed.into_iter().map(Result::unwrap).collect::<Vec<_>>(),
+                   tokens);
+        assert_eq!(streamed_errors.into_iter().map(Result::unwrap_err).collect::<Vec<_>>(),
+                   errors);
+    }
+
+    /// A source that fails after its first line
+    struct BrokenSource(bool);
+
+    impl Read for BrokenSource {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0 {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, "broken"));
+            }
+            self.0 = true;
+            let line = b"nop\n";
+            buf[..line.len()].copy_from_slice(line);
+            Ok(line.len())
+        }
+    }
+
+    #[test]
+    fn test_stream_incremental() {
+        let mut stream = Tokenizer::new().stream(BrokenSource(false));
+        // the first line is available before the source fails
+        assert!(stream.next().unwrap().unwrap().is_identifier());
+        assert!(stream.next().unwrap().unwrap().is_newline());
+        let error = TokenizerError::Io(Position { row: 2, column: 1, offset: 4 },
+                                       io::ErrorKind::BrokenPipe);
+        assert_eq!(stream.next(), Some(Err(error)));
+        assert_eq!(stream.next(), None);
+    }
 }